@@ -0,0 +1,55 @@
+use crate::tokenizer::openai::OpenAI;
+
+/// Common abstraction of the token counters.
+pub trait Counter {
+    fn count(&self) -> usize;
+}
+
+/// Counter of the tokens in a borrowed text.
+///
+/// `Counter::count` doesn't take any argument, so this struct binds a tokenizer and the text
+/// to be counted. Both are borrowed so that one tokenizer can be shared by many counters.
+/// The text is counted as ordinary text, which means special tokens in the text are counted
+/// as normal characters.
+pub struct TextCounter<'a> {
+    tokenizer: &'a OpenAI,
+    text: &'a str,
+}
+
+impl <'a> TextCounter<'a> {
+    pub fn new(tokenizer: &'a OpenAI, text: &'a str) -> Self {
+        Self {
+            tokenizer,
+            text,
+        }
+    }
+}
+
+impl Counter for TextCounter<'_> {
+    fn count(&self) -> usize {
+        self.tokenizer.count_ordinary(self.text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::counter::{Counter, TextCounter};
+    use crate::tokenizer::openai::Models;
+    use crate::tokenizer::openai::testing::{model_pattern, test_tokenizer};
+
+    #[test]
+    fn test_text_counter() {
+        let tokenizer = test_tokenizer();
+        let text = "hello world, the thing is nothing.";
+        let counter = TextCounter::new(&tokenizer, text);
+
+        assert_eq!(counter.count(), tokenizer.encode_ordinary(text).len());
+
+        // With the real cl100k pattern, "don't" is "don" + "'t" and the digits are split by three.
+        let tokenizer = test_tokenizer().with_pattern(&model_pattern(Models::CL100KBase)).unwrap();
+        let text = "hello world, don't 12345";
+        // "hello", " world", ",", " don" (4 bytes), "'t" (2 bytes), " ", "123" (3 bytes), "45" (2 bytes)
+        assert_eq!(TextCounter::new(&tokenizer, text).count(), 15);
+        assert_eq!(TextCounter::new(&tokenizer, text).count(), tokenizer.encode_ordinary(text).len());
+    }
+}
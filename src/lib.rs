@@ -1,2 +1,3 @@
-mod tokenizer;
-mod errors;
+pub mod tokenizer;
+pub mod errors;
+pub mod counter;
\ No newline at end of file
@@ -1,2 +1,6 @@
-mod openai;
-mod utils;
\ No newline at end of file
+pub mod openai;
+mod diff;
+mod utils;
+
+pub use diff::{token_diff, DiffOp};
+pub use utils::{count_invalid_bytes, from_utf8_replace};
@@ -0,0 +1,72 @@
+/// An operation of the token-level diff returned by `token_diff`.
+/// 'Equal' is a token in both, 'Insert' is only in the second and 'Delete' is only in the first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DiffOp {
+    Equal(u32),
+    Insert(u32),
+    Delete(u32),
+}
+
+/// Computes where two token sequences diverge by the longest common subsequence.
+///
+/// This needs O(n * m) time and memory for the sequences of the lengths n and m,
+/// so it's meant for comparing prompts rather than whole documents.
+///
+/// # Arguments
+///
+/// * `a` - The original tokens.
+/// * `b` - The modified tokens.
+///
+/// # Returns
+///
+/// A vector of `DiffOp` which turns `a` into `b` when applied from the beginning.
+/// The deletions come before the insertions at the same position.
+pub fn token_diff(a: &[u32], b: &[u32]) -> Vec<DiffOp> {
+    // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..].
+    let mut lcs = vec![vec![0_usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::with_capacity(a.len().max(b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            ops.push(DiffOp::Equal(a[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            ops.push(DiffOp::Delete(a[i]));
+            i += 1;
+        } else {
+            ops.push(DiffOp::Insert(b[j]));
+            j += 1;
+        }
+    }
+    ops.extend(a[i..].iter().map(|token| DiffOp::Delete(*token)));
+    ops.extend(b[j..].iter().map(|token| DiffOp::Insert(*token)));
+
+    ops
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tokenizer::diff::{token_diff, DiffOp};
+
+    #[test]
+    fn test_token_diff() {
+        assert_eq!(token_diff(&[1, 2, 3], &[1, 2, 9, 3]),
+                   vec![DiffOp::Equal(1), DiffOp::Equal(2), DiffOp::Insert(9), DiffOp::Equal(3)]);
+        assert_eq!(token_diff(&[1, 2, 3], &[1, 4, 3]),
+                   vec![DiffOp::Equal(1), DiffOp::Delete(2), DiffOp::Insert(4), DiffOp::Equal(3)]);
+        assert_eq!(token_diff(&[5, 6], &[]), vec![DiffOp::Delete(5), DiffOp::Delete(6)]);
+        assert_eq!(token_diff(&[], &[7]), vec![DiffOp::Insert(7)]);
+        assert!(token_diff(&[], &[]).is_empty());
+    }
+}
@@ -1,28 +1,52 @@
+use std::borrow::Cow;
 use std::cmp::max;
 use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::io::Write;
 use std::str::from_utf8;
+use std::sync::Arc;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use rayon::prelude::*;
 use regex::Regex;
 use rustc_hash::FxHashMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use unicode_normalization::UnicodeNormalization;
 use crate::tokenizer::openai::bpe::CoreBytePairEncoding;
-use crate::tokenizer::utils::{from_utf8_backslash, from_utf8_ignore};
+use crate::tokenizer::openai::openai_sets::PATTERN_PROBE;
+use crate::tokenizer::utils::{from_utf8_backslash, from_utf8_ignore, from_utf8_or, from_utf8_replace};
 use crate::errors::{CounterError, CounterResult};
 
-pub(super) mod models;
+pub mod models;
 pub(super) mod load;
+#[cfg(feature = "async")]
+pub mod load_async;
 pub(super) mod bpe;
+pub mod builder;
+pub mod chat;
+mod hf;
 mod openai_sets;
+pub mod stream;
+#[cfg(test)]
+pub(crate) mod testing;
+
+pub use openai_sets::Models;
+pub use load::{bytes_to_unicode, dump_bpe, dump_bpe_to_writer, load_bpe_path, load_bpe_with_options, unicode_to_bytes, LoadOptions};
 
 /// When encode text, you can specify special characters as allowed or disallowed.
 /// In the OpenAI encode methods, `allowed_special` is preferred so both of allowed and disallowed
 /// is specified as `All`, all specials inputted as dictionary assign to `allowed_special` and
 /// none of them to `disallowed_special`.
-#[derive(Clone, PartialEq)]
+/// `Owned` is the same as `Collection` but takes a set built at runtime without borrowing it.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Specials<'a> {
     All,
     Collection(&'a[&'a str]),
+    Owned(HashSet<String>),
 }
 
 /// When you want to get single token, you can through `&str` or `&[u8](Bytes)`.
+#[derive(Debug)]
 pub enum SingleInput<'a> {
     String(&'a str),
     Bytes(&'a[u8]),
@@ -33,7 +57,7 @@ pub enum SingleInput<'a> {
 /// For 'Replace', the invalid bytes will be replaced with "\u{FFFD}", about 'Ignore',
 /// the invalid bytes will be ignored.
 /// If you select 'BackSlashReplace', the invalid each byte convert to escape sequence like '\xNN'.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum DecodeErrorHandler {
     Strict,
     Replace,
@@ -41,25 +65,92 @@ pub enum DecodeErrorHandler {
     BackSlashReplace,
 }
 
-#[derive(Clone)]
+/// The handling of a disallowed special token found by `OpenAI::encode_with_action`.
+/// 'Error' rejects the text like `OpenAI::encode` and 'EncodeAsText' encodes the text
+/// of the special token as ordinary content.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DisallowedAction {
+    Error,
+    EncodeAsText,
+}
+
+/// The side of the tokens to be truncated when the tokens exceed the budget.
+/// 'Right' drops the tail and keeps the head, 'Left' drops the head and keeps the tail.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TruncationSide {
+    Left,
+    Right,
+}
+
+/// The Unicode normalization form applied by `OpenAI::encode_ordinary_normalized`.
+/// 'Nfc' and 'Nfd' are the canonical composition and decomposition,
+/// 'Nfkc' and 'Nfkd' are the compatibility ones which also fold e.g. the full-width letters.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NormalizationForm {
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+}
+
+/// The kind of the token returned by `OpenAI::token_kind`.
+/// 'Byte' is a single-byte token, 'Merge' is a token merged from multiple bytes
+/// and 'Special' is a special control token like `<|endoftext|>`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Byte,
+    Merge,
+    Special,
+}
+
+/// The aggregate statistics of the vocabulary returned by `OpenAI::vocab_stats`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VocabStats {
+    /// The number of the mergeable tokens.
+    pub n_merge_tokens: usize,
+    /// The number of the special tokens.
+    pub n_special_tokens: usize,
+    /// The smallest token id including the special tokens.
+    pub min_id: u32,
+    /// The largest token id including the special tokens.
+    pub max_id: u32,
+    /// Whether the token ids cover `min_id..=max_id` without any gap or duplicate.
+    pub is_contiguous: bool,
+}
+
+/// The serialized form keeps the bytes of the merge ranks as base64 strings like the `.tiktoken` file.
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct OpenAIInput {
     name: String,
     pattern: String,
+    #[serde(serialize_with = "serialize_ranks", deserialize_with = "deserialize_ranks")]
     merge_able_ranks: HashMap<Vec<u8>, u32>,
     special_tokens: HashMap<String, u32>,
     explicit_n_vocab: Option<u32>,
+    /// The number of the pieces which the pattern splits `PATTERN_PROBE` into for the built-in encodings.
+    #[serde(default)]
+    expected_probe_pieces: Option<usize>,
+    /// Whether the merge ranks must have a token for every single byte, which the built-in encodings require.
+    #[serde(default)]
+    require_full_byte_coverage: bool,
 }
 
 /// OpenAI API tokenizer struct based on BPE(Byte Pair Encoding)
 /// This code based on the tiktoken (https://github.com/openai/tiktoken)
 /// But current implementation doesn't support parallel execution.
-pub(crate) struct OpenAI {
+///
+/// The tokenizer is `Send` and `Sync`, so one instance can be shared across threads
+/// behind an `Arc` (e.g. stored in the application state of a web server).
+/// Cloning is also cheap since the merge ranks and the BPE tables are shared by `Arc`.
+#[derive(Clone)]
+pub struct OpenAI {
     name: String,
     pattern: String,
-    merge_able_ranks: FxHashMap<Vec<u8>, u32>,
+    merge_able_ranks: Arc<FxHashMap<Vec<u8>, u32>>,
     special_token: FxHashMap<String, u32>,
     max_token_value: u32,
     bpe_base: CoreBytePairEncoding,
+    expected_probe_pieces: Option<usize>,
 }
 
 impl <'a> OpenAI {
@@ -72,6 +163,10 @@ impl <'a> OpenAI {
     /// * `merge_able_ranks` - A `HashMap` mapping byte sequences to merge ranks.
     /// * `special_tokens` - A `HashMap` mapping special tokens to ids.
     /// * `explicit_n_vocab` - An optional explicit number of vocabulary tokens.
+    ///   When it is specified, the ids are validated to be contiguous by `validate_ranks`.
+    ///
+    /// The merge ranks missing single bytes are accepted as they are.
+    /// Use `with_byte_coverage_check` to reject them.
     ///
     /// # Returns
     ///
@@ -83,7 +178,11 @@ impl <'a> OpenAI {
                special_tokens: HashMap<String, u32>,
                explicit_n_vocab: Option<u32>
     ) -> CounterResult<Self> {
-        let fx_ranks = FxHashMap::from_iter(merge_able_ranks);
+        if explicit_n_vocab.is_some() {
+            validate_ranks(&merge_able_ranks, &special_tokens)?;
+        }
+
+        let fx_ranks = Arc::new(FxHashMap::from_iter(merge_able_ranks));
         let fx_special_tokens = FxHashMap::from_iter(special_tokens);
 
         let max_merge_ranks = match fx_ranks.values().max() {
@@ -102,12 +201,19 @@ impl <'a> OpenAI {
         let max_token_value = max(max_merge_ranks, max_special_tokens);
 
         if let Some(explicit_vocabs) = explicit_n_vocab {
-            assert_eq!((fx_ranks.len() + fx_special_tokens.len()) as u32, explicit_vocabs);
-            assert_eq!(max_token_value, explicit_vocabs - 1);
+            let n_tokens = fx_ranks.len() + fx_special_tokens.len();
+            if n_tokens as u64 != explicit_vocabs as u64 {
+                return Err(CounterError::ValueError(format!(
+                    "explicit_n_vocab is {} but the input has {} tokens.", explicit_vocabs, n_tokens)))
+            }
+            if explicit_vocabs.checked_sub(1) != Some(max_token_value) {
+                return Err(CounterError::ValueError(format!(
+                    "explicit_n_vocab is {} but the max token id is {}.", explicit_vocabs, max_token_value)))
+            }
         }
 
         let bpe =
-            CoreBytePairEncoding::new(fx_ranks.clone(),
+            CoreBytePairEncoding::new(Arc::clone(&fx_ranks),
                                       fx_special_tokens.clone(),
                                       &pattern_str)?;
 
@@ -118,15 +224,166 @@ impl <'a> OpenAI {
             special_token: fx_special_tokens,
             max_token_value,
             bpe_base: bpe,
+            expected_probe_pieces: None,
+        })
+    }
+
+    /// Constructs a tokenizer from the JSON serialized by `to_serialized`.
+    ///
+    /// This avoids downloading and parsing the vocabulary files on every startup.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The serialized tokenizer configuration.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` that resolves to an `OpenAI` tokenizer on success,
+    /// or `CounterError::ValueError` if the bytes are not a valid configuration.
+    pub fn from_serialized(bytes: &[u8]) -> CounterResult<Self> {
+        let input = serde_json::from_slice::<OpenAIInput>(bytes)
+            .map_err(|e| CounterError::ValueError(e.to_string()))?;
+
+        Self::try_from(input)
+    }
+
+    /// Constructs the tokenizer of a built-in encoding from the preloaded merge ranks,
+    /// e.g. the vocabulary file vendored into the application instead of downloading it.
+    ///
+    /// The pattern, the special tokens and the number of the vocabulary are filled in from the encoding.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The built-in encoding.
+    /// * `merge_able_ranks` - The merge ranks of the encoding, e.g. loaded by `load_bpe_path`.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` that resolves to an `OpenAI` tokenizer on success,
+    /// or `CounterError::ValueError` if the number of the ranks doesn't match the encoding.
+    pub fn from_model_ranks(model: Models, merge_able_ranks: HashMap<Vec<u8>, u32>) -> CounterResult<Self> {
+        Self::try_from(model.get_input_with_ranks(merge_able_ranks)?)
+    }
+
+    /// Serializes the name, the pattern, the merge ranks and the special tokens into JSON,
+    /// which can be loaded by `from_serialized`.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` containing the serialized bytes.
+    pub fn to_serialized(&self) -> CounterResult<Vec<u8>> {
+        let input = OpenAIInput {
+            name: self.name.clone(),
+            pattern: self.pattern.clone(),
+            merge_able_ranks: self.merge_able_ranks.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            special_tokens: self.special_token.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            explicit_n_vocab: None,
+            expected_probe_pieces: self.expected_probe_pieces,
+            require_full_byte_coverage: false,
+        };
+
+        serde_json::to_vec(&input).map_err(|e| CounterError::ValueError(e.to_string()))
+    }
+
+    /// Adds the special tokens, e.g. the control tokens like `<|im_start|>` added by fine-tuning.
+    ///
+    /// The merge ranks are shared with the original tokenizer and only the BPE core is rebuilt
+    /// with the union of the special tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `extra` - A `HashMap` mapping the additional special tokens to ids.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` that resolves to the new `OpenAI` tokenizer on success,
+    /// or `CounterError::ValueError` if an id collides with a merge rank or another special token.
+    pub fn with_additional_specials(self, extra: HashMap<String, u32>) -> CounterResult<OpenAI> {
+        let mut special_token = self.special_token.clone();
+        for (token, id) in extra {
+            if let Some(old_id) = special_token.insert(token.clone(), id) {
+                if old_id != id {
+                    return Err(CounterError::ValueError(format!(
+                        "special token '{}' already has the id {} but {} is given.", token, old_id, id)))
+                }
+            }
+        }
+        check_special_ids(&self.merge_able_ranks, &special_token)?;
+
+        let bpe_base =
+            CoreBytePairEncoding::new(Arc::clone(&self.merge_able_ranks),
+                                      special_token.clone(),
+                                      &self.pattern)?
+                .with_max_piece_bytes(self.bpe_base.max_piece_bytes());
+        let max_token_value = max(self.max_token_value, special_token.values().max().cloned().unwrap_or(0));
+
+        Ok(Self {
+            name: self.name,
+            pattern: self.pattern,
+            merge_able_ranks: self.merge_able_ranks,
+            special_token,
+            max_token_value,
+            bpe_base,
+            expected_probe_pieces: self.expected_probe_pieces,
+        })
+    }
+
+    /// Replaces the pre-tokenization pattern, e.g. for the A/B testing of the pre-tokenization.
+    ///
+    /// The merge ranks are shared with the original tokenizer and only the BPE core is rebuilt
+    /// with the new pattern.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The new pre-tokenization pattern.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` that resolves to the new `OpenAI` tokenizer on success,
+    /// or `CounterError::RegexError` if the pattern doesn't compile.
+    pub fn with_pattern(self, pattern: &str) -> CounterResult<OpenAI> {
+        let bpe_base =
+            CoreBytePairEncoding::new(Arc::clone(&self.merge_able_ranks),
+                                      self.special_token.clone(),
+                                      pattern)?
+                .with_max_piece_bytes(self.bpe_base.max_piece_bytes());
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            bpe_base,
+            ..self
         })
     }
 
+    /// Sets the maximum byte length of a pre-tokenized piece encoded by the byte pair merges.
+    ///
+    /// A longer piece is split into the chunks of `max_piece_bytes` bytes before merging, which bounds
+    /// the cost of the merges on an adversarial input like a giant piece without any whitespace.
+    /// The tradeoff is that the tokens of such pieces are not canonical, i.e. they may differ from
+    /// those of the other tokenizers, though they still decode to the same text.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_piece_bytes` - The maximum byte length of a piece, or `None` (the default) not to split.
+    ///
+    /// # Returns
+    ///
+    /// The tokenizer splitting the long pieces.
+    pub fn with_max_piece_bytes(mut self, max_piece_bytes: Option<usize>) -> Self {
+        self.bpe_base = self.bpe_base.with_max_piece_bytes(max_piece_bytes);
+        self
+    }
+
     // ===================
     // Encoding
     // ===================
 
     /// Encodes ordinary text into a sequence of tokens.
     ///
+    /// If the pre-tokenization regex fails on the text, e.g. a custom pattern exceeds
+    /// the backtracking limit, the whole text is encoded as one BPE piece instead of panicking,
+    /// so the tokens still decode to the text. Use `try_encode_ordinary` to detect the failure.
+    ///
     /// # Arguments
     ///
     /// * `text` - The text to be encoded.
@@ -135,9 +392,287 @@ impl <'a> OpenAI {
     ///
     /// A vector of `u32` values representing encoded tokens.
     pub fn encode_ordinary(&self, text: &str) -> Vec<u32> {
+        self.bpe_base.encode_ordinary(text).unwrap_or_else(|_| self.encode_unsplit(text))
+    }
+
+    /// Encodes ordinary text into a sequence of tokens like `encode_ordinary`,
+    /// but surfaces the failure of the pre-tokenization regex instead of falling back.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the encoded tokens,
+    /// or `CounterError::RegexError` if the pre-tokenization regex fails on the text.
+    pub fn try_encode_ordinary(&self, text: &str) -> CounterResult<Vec<u32>> {
         self.bpe_base.encode_ordinary(text)
     }
 
+    /// Encodes ordinary text and appends the tokens into the given buffer.
+    ///
+    /// Reusing the buffer avoids the allocation per call in a hot loop.
+    /// The existing contents of the buffer are kept, so clear it beforehand if needed.
+    /// The regex failure falls back like `encode_ordinary`.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    /// * `out` - The buffer to which the encoded tokens are appended.
+    pub fn encode_ordinary_into(&self, text: &str, out: &mut Vec<u32>) {
+        let len = out.len();
+        if self.bpe_base.encode_ordinary_into(text, out).is_err() {
+            out.truncate(len);
+            out.extend(self.encode_unsplit(text));
+        }
+    }
+
+    /// Encodes ordinary text and truncates the tokens to the maximum token budget.
+    ///
+    /// For `TruncationSide::Right`, the encoding stops as soon as the budget is reached.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    /// * `max_tokens` - The maximum number of the tokens.
+    /// * `side` - The side of the tokens to be truncated.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing at most `max_tokens` tokens.
+    pub fn encode_truncated(&self,
+                            text: &str,
+                            max_tokens: usize,
+                            side: TruncationSide
+    ) -> CounterResult<Vec<u32>> {
+        let tokens = match side {
+            TruncationSide::Right => self.bpe_base.encode_ordinary_head(text, max_tokens)?,
+            TruncationSide::Left => {
+                let mut tokens = self.bpe_base.encode_ordinary(text)?;
+                tokens.drain(..tokens.len().saturating_sub(max_tokens));
+                tokens
+            }
+        };
+
+        Ok(tokens)
+    }
+
+    /// Encodes ordinary text and returns at most the last `n` tokens,
+    /// e.g. the tail of a growing prompt fitting in the context window.
+    ///
+    /// The tokens are the same as the tail of `encode_ordinary(text)`.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    /// * `n` - The maximum number of the tokens from the end.
+    ///
+    /// # Returns
+    ///
+    /// A vector of the last `n` tokens, or all the tokens if there are fewer.
+    pub fn encode_tail(&self, text: &str, n: usize) -> Vec<u32> {
+        // TODO optimize: encode only from a piece boundary near the end instead of the whole text.
+        let mut tokens = self.encode_ordinary(text);
+        tokens.drain(..tokens.len().saturating_sub(n));
+        tokens
+    }
+
+    /// Splits the text into overlapping chunks having at most `max_tokens` tokens.
+    ///
+    /// The text is encoded once and the tokens are windowed with the stride of `max_tokens - overlap`.
+    /// Each window is decoded with `DecodeErrorHandler::Replace`, so a multibyte character split at
+    /// the window boundary becomes "\u{FFFD}" instead of failing.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be split.
+    /// * `max_tokens` - The maximum number of the tokens in a chunk.
+    /// * `overlap` - The number of the tokens shared by the adjacent chunks.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the decoded chunks,
+    /// or `CounterError::ValueError` if `overlap` is not less than `max_tokens`.
+    pub fn chunk_by_tokens(&self,
+                           text: &str,
+                           max_tokens: usize,
+                           overlap: usize
+    ) -> CounterResult<Vec<String>> {
+        if overlap >= max_tokens {
+            return Err(CounterError::ValueError(format!(
+                "overlap ({}) must be less than max_tokens ({}).", overlap, max_tokens)))
+        }
+
+        let tokens = self.bpe_base.encode_ordinary(text)?;
+        let stride = max_tokens - overlap;
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < tokens.len() {
+            let end = (start + max_tokens).min(tokens.len());
+            chunks.push(self.decode(&tokens[start..end], DecodeErrorHandler::Replace)?);
+            if end == tokens.len() {
+                break
+            }
+            start += stride;
+        }
+
+        Ok(chunks)
+    }
+
+    /// Encodes ordinary text using a caller-supplied pre-tokenization pattern
+    /// instead of the pattern of the tokenizer.
+    ///
+    /// The merge ranks of the tokenizer are used as they are, so the result may differ from the
+    /// canonical tokenization of the model. This is intended for experiments of the pre-tokenization.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    /// * `pattern` - The compiled regex splitting the text into pieces.
+    ///   Look-around assertions are supported as in the pattern of the tokenizer.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the encoded tokens,
+    /// or `CounterError::RegexError` if the pattern fails to match, e.g. by exceeding the backtrack limit.
+    pub fn encode_ordinary_with_pattern(&self, text: &str, pattern: &fancy_regex::Regex) -> CounterResult<Vec<u32>> {
+        self.bpe_base.encode_ordinary_with_regex(text, pattern)
+    }
+
+    /// Encodes ordinary text into a sequence of tokens with the byte span of each token.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    ///
+    /// # Returns
+    ///
+    /// A tuple of the encoded tokens and the `[start, end)` byte ranges in `text` covered by
+    /// each token. A span can split a multibyte character when the token has a part of the character.
+    /// The regex failure falls back like `encode_ordinary`.
+    pub fn encode_with_offsets(&self, text: &str) -> (Vec<u32>, Vec<(usize, usize)>) {
+        self.bpe_base.encode_ordinary_with_offsets(text).unwrap_or_else(|_| {
+            let tokens = self.encode_unsplit(text);
+            let mut start = 0;
+            let offsets = tokens
+                .iter()
+                .map(|token| {
+                    let end = start + self.bpe_base.decode_single_token_bytes_ref(*token).map_or(0, |bytes| bytes.len());
+                    (std::mem::replace(&mut start, end), end)
+                })
+                .collect();
+            (tokens, offsets)
+        })
+    }
+
+    /// Encodes ordinary text marking whether each token needed the byte pair merges.
+    ///
+    /// A token is marked `false` when its regex piece is a token of the vocabulary as a whole,
+    /// and `true` when the piece was split by BPE, which indicates rare content for the vocabulary.
+    /// If the regex fails, all the tokens are marked `true` as the text falls back to one BPE piece.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    ///
+    /// # Returns
+    ///
+    /// A vector of the tokens paired with the fallback flags, whose tokens are equal to `encode_ordinary`.
+    pub fn encode_ordinary_annotated(&self, text: &str) -> Vec<(u32, bool)> {
+        self.bpe_base.encode_ordinary_annotated(text).unwrap_or_else(|_| {
+            self.encode_unsplit(text).into_iter().map(|token| (token, true)).collect()
+        })
+    }
+
+    /// Encodes ordinary text after applying the Unicode normalization, so the visually identical
+    /// texts from different sources (e.g. "é" as one code point or "e" with a combining accent)
+    /// are encoded into the same tokens. The other encoding methods don't normalize the text.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    /// * `form` - The normalization form applied before encoding.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `u32` values representing the encoded tokens of the normalized text.
+    pub fn encode_ordinary_normalized(&self, text: &str, form: NormalizationForm) -> Vec<u32> {
+        let normalized = match form {
+            NormalizationForm::Nfc => text.nfc().collect::<String>(),
+            NormalizationForm::Nfd => text.nfd().collect::<String>(),
+            NormalizationForm::Nfkc => text.nfkc().collect::<String>(),
+            NormalizationForm::Nfkd => text.nfkd().collect::<String>(),
+        };
+
+        self.encode_ordinary(&normalized)
+    }
+
+    /// Encodes ordinary text pairing each token with its decoded fragment, e.g. for token visualizers.
+    ///
+    /// Concatenating the fragments reproduces the input as long as every token ends on a character
+    /// boundary. A token holding a part of a multibyte character (e.g. an emoji split into
+    /// two tokens) is decoded with `Replace`, so its fragment has "\u{FFFD}" instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the tokens paired with their fragments,
+    /// or `CounterError::RegexError` if the pre-tokenization regex fails on the text.
+    pub fn encode_with_pieces(&self, text: &str) -> CounterResult<Vec<(u32, String)>> {
+        self.try_encode_ordinary(text)?
+            .into_iter()
+            .map(|token| {
+                let bytes = self.bpe_base.decode_single_token_bytes_ref(token)?;
+                Ok((token, String::from_utf8_lossy(bytes).into_owned()))
+            })
+            .collect()
+    }
+
+    /// Encodes ordinary text wrapped with the begin-of-sequence and end-of-sequence tokens
+    /// which some downstream models expect.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    /// * `bos` - The token prepended to the tokens, or `None` not to prepend.
+    /// * `eos` - The token appended to the tokens, or `None` not to append.
+    ///
+    /// # Returns
+    ///
+    /// A vector of the tokens of `encode_ordinary` wrapped with the given tokens.
+    pub fn encode_with_bos_eos(&self, text: &str, bos: Option<u32>, eos: Option<u32>) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        tokens.extend(bos);
+        self.encode_ordinary_into(text, &mut tokens);
+        tokens.extend(eos);
+        tokens
+    }
+
+    /// Counts the single-byte tokens of the encoded text by their byte values,
+    /// e.g. to diagnose mojibake or a script which the vocabulary barely covers.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    ///
+    /// # Returns
+    ///
+    /// A `HashMap` mapping the byte values of the emitted single-byte tokens to their frequencies.
+    pub fn byte_fallback_report(&self, text: &str) -> HashMap<u8, usize> {
+        let mut report = HashMap::new();
+        for token in self.encode_ordinary(text) {
+            if let Ok([byte]) = self.bpe_base.decode_single_token_bytes_ref(token) {
+                *report.entry(*byte).or_insert(0) += 1;
+            }
+        }
+        report
+    }
+
     /// Encodes the given text using the specified allowed and disallowed special characters.
     ///
     /// Returns a `Result` that contains a vector of encoded values on success, or an error message on failure.
@@ -162,7 +697,39 @@ impl <'a> OpenAI {
                                      allowed_special.clone(),
                                      disallowed_special)?;
 
-        Ok(self.bpe_base.encode(text, allowed_special))
+        self.bpe_base.encode(text, allowed_special)
+    }
+
+    /// Encodes the given text like `encode` with the handling of the disallowed special tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be encoded.
+    /// * `allowed_special` - The special tokens encoded as the special token ids.
+    /// * `disallowed_special` - The special tokens which are not expected in the text.
+    /// * `action` - The handling when a disallowed special token is found.
+    ///   With `EncodeAsText`, every special token which is not allowed is encoded as ordinary text,
+    ///   so `disallowed_special` doesn't matter.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the encoded tokens,
+    /// or `CounterError::ValueError` if a disallowed special token is found with `Error`.
+    pub fn encode_with_action(&self,
+                              text: &str,
+                              allowed_special: Specials<'_>,
+                              disallowed_special: Specials<'_>,
+                              action: DisallowedAction
+    ) -> CounterResult<Vec<u32>> {
+        match action {
+            DisallowedAction::Error => self.encode(text, allowed_special, disallowed_special),
+            DisallowedAction::EncodeAsText => {
+                let allowed_special =
+                    self.validation_specials(text, allowed_special, Specials::Collection(&[]))?;
+
+                self.bpe_base.encode(text, allowed_special)
+            }
+        }
     }
 
     /// Encodes a batch of ordinary text into a Vec of tokens vector.
@@ -176,7 +743,7 @@ impl <'a> OpenAI {
     /// A Vec constructed from Vec of u32, where each inner Vec corresponds
     /// to the encoded token of a text element in `text`.
     pub fn encode_ordinary_batch(&self, text: &[&str]) -> Vec<Vec<u32>> {
-        text.iter().map(|str| self.bpe_base.encode_ordinary(str)).collect::<Vec<_>>()
+        text.iter().map(|str| self.encode_ordinary(str)).collect::<Vec<_>>()
     }
 
     /// Encodes a batch of text into a vector of encoded tokens.
@@ -205,6 +772,34 @@ impl <'a> OpenAI {
         Ok(tokens)
     }
 
+    /// Encodes a batch of text given as any iterable of string-like values,
+    /// e.g. `Vec<String>`, arrays or iterators, without collecting them into `&[&str]`.
+    ///
+    /// # Arguments
+    ///
+    /// * `texts` - An iterable of the text to be encoded.
+    /// * `allowed_special` - An instance of `Specials` representing the special characters allowed during encoding.
+    /// * `disallowed_special` - An instance of `Specials` representing the special characters disallowed during encoding.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the vector of encoded tokens vec,
+    /// or an error if encoding fails for any text in `texts`.
+    pub fn encode_batch_iter<I, S>(&self,
+                                   texts: I,
+                                   allowed_special: Specials<'a>,
+                                   disallowed_special: Specials<'a>
+    ) -> CounterResult<Vec<Vec<u32>>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        texts
+            .into_iter()
+            .map(|text| self.encode(text.as_ref(), allowed_special.clone(), disallowed_special.clone()))
+            .collect()
+    }
+
     /// Encodes the given `text` using the unstable method.
     ///
     /// Special characters allowed in the encoding can be specified using the `allowed_special`
@@ -239,7 +834,7 @@ impl <'a> OpenAI {
                                      allowed_special.clone(),
                                      disallowed_special)?;
 
-        Ok(self.bpe_base.encode_with_unstable(text, allowed_special))
+        self.bpe_base.encode_with_unstable(text, allowed_special)
     }
 
     /// Encodes a single input into a token.
@@ -263,273 +858,2384 @@ impl <'a> OpenAI {
         }
     }
 
-    // ===================
-    // Decoding
-    // ===================
-
-    /// Decode to vector of bytes from a given token.
+    /// Splits the text by the pre-tokenization regex, which is the first stage of the encoding
+    /// before BPE is applied to each piece, e.g. for debugging a custom pattern.
+    ///
+    /// If the regex fails on the text, the whole text is returned as one piece
+    /// like the fallback of `encode_ordinary`.
     ///
     /// # Arguments
     ///
-    /// * `token` - A slice of u32 values representing the tokens.
+    /// * `text` - The text to be split.
     ///
     /// # Returns
     ///
-    /// Returns a bytes as vector of u8.
-    pub fn decode_bytes(&self, token: &[u32]) -> Vec<u8> {
-        self.bpe_base.decode_bytes(token)
+    /// A vector of the pieces borrowed from the text, whose concatenation is the text.
+    pub fn pretokenize<'b>(&self, text: &'b str) -> Vec<&'b str> {
+        self.bpe_base.pretokenize(text).unwrap_or_else(|_| {
+            if text.is_empty() { vec![] } else { vec![text] }
+        })
     }
 
-    /// Decodes a token into a string.
+    /// Encodes a single piece into as many tokens as it takes by BPE.
+    ///
+    /// Unlike `encode_single_token`, which fails with `KeyError` unless the whole piece is
+    /// exactly one token, this method falls back to the byte pair merges. The piece is not split
+    /// by the pre-tokenization regex, so the tokens may differ from `encode_ordinary`.
     ///
     /// # Arguments
     ///
-    /// * `token` - The token to decode, represented as a slice of `u32` values.
-    /// * `errors` - The error handling strategy when decoding fails.
+    /// * `bytes` - The piece to be encoded.
     ///
-    /// # Errors
+    /// # Returns
     ///
-    /// Returns a `CounterError` if decoding fails and the error handling strategy is set to `Strict`.
+    /// A vector of `u32` values representing encoded tokens.
+    pub fn encode_piece(&self, bytes: &[u8]) -> Vec<u32> {
+        self.bpe_base.encode_single_piece(bytes)
+    }
+
+    /// Encodes a single string or bytes fragment into as many tokens as it takes by BPE.
+    ///
+    /// This is `encode_piece` accepting the same `SingleInput` as `encode_single_token`.
+    /// Where `encode_single_token` fails unless the input is exactly one token, this returns
+    /// one or more tokens. The input is not split by the pre-tokenization regex either.
+    ///
+    /// # Arguments
+    ///
+    /// * `text_or_bytes` - The single input represent string or bytes to be encoded.
     ///
     /// # Returns
     ///
-    /// Returns a `CounterResult` containing the decoded string on success,
-    /// or the decoded replacement string based on the error handling strategy.
-    pub fn decode(&self, token: &[u32], errors: DecodeErrorHandler) -> CounterResult<String> {
-        let bytes = self.bpe_base.decode_bytes(token);
-
-        let decoded_str = match from_utf8(&bytes) {
-            Ok(decoded_str) => decoded_str.to_string(),
-            Err(e) => {
-                match errors {
-                    DecodeErrorHandler::Strict => return Err(CounterError::ByteDecodeError(e.to_string())),
-                    DecodeErrorHandler::Replace => {
-                        String::from_utf8_lossy(&bytes).to_string()
-                    }
-                    DecodeErrorHandler::Ignore => from_utf8_ignore(&bytes).to_string(),
-                    DecodeErrorHandler::BackSlashReplace => from_utf8_backslash(&bytes).to_string(),
-                }
-            }
-        };
-        Ok(decoded_str)
+    /// A vector of `u32` values representing encoded tokens.
+    pub fn encode_single_piece(&self, text_or_bytes: SingleInput) -> Vec<u32> {
+        match text_or_bytes {
+            SingleInput::String(str) => self.encode_piece(str.as_bytes()),
+            SingleInput::Bytes(bytes) => self.encode_piece(bytes),
+        }
     }
 
-    /// Decodes a single token into a vector of bytes.
+    /// Looks up the longest single token which is a prefix of the text.
+    ///
+    /// This is a lookup helper for the vocabulary, not how BPE tokenizes the text:
+    /// the first token of `encode_ordinary(text)` may be shorter than the returned one.
+    /// The special tokens are not looked up.
     ///
     /// # Arguments
     ///
-    /// * `token` - The token to decode.
+    /// * `text` - The text whose prefix is looked up.
     ///
     /// # Returns
     ///
-    /// A `CounterResult` containing the decoded vector of bytes.
-    pub fn decode_single_tokens_bytes(self, token: u32) -> CounterResult<Vec<u8>> {
-        self.bpe_base.decode_single_token_bytes(token)
+    /// An `Option` containing the token and its length in bytes, or `None` if no token matches
+    /// (e.g. the text is empty).
+    pub fn longest_token_prefix(&self, text: &str) -> Option<(u32, usize)> {
+        self.bpe_base.longest_token_prefix(text.as_bytes())
     }
 
-    /// Decode a slice of tokens into a vector of byte vectors.
+    /// Encodes arbitrary bytes, which may not be valid UTF-8, ignoring the special tokens.
+    ///
+    /// The valid UTF-8 prefix is encoded like `encode_ordinary`. Its last regex piece
+    /// (with the preceding whitespace tokens) and the bytes after the first invalid byte are
+    /// encoded together by BPE as one piece, so `decode_bytes(&encode_bytes(bytes)) == bytes`
+    /// holds for any input.
     ///
     /// # Arguments
     ///
-    /// * `tokens` - A slice of u32 tokens representing the encoded tokens.
+    /// * `bytes` - The bytes to be encoded.
     ///
     /// # Returns
     ///
-    /// A `CounterResult` containing vector of the decoded byte vectors,
-    /// or an error if the decoding fails.
-    pub fn decode_tokens_bytes(self, tokens: &[u32]) -> CounterResult<Vec<Vec<u8>>> {
-        let mut res = Vec::new();
-
-        for token in tokens {
-            res.push(self.bpe_base.decode_single_token_bytes(*token)?);
-        }
-
-        Ok(res)
+    /// A vector of `u32` values representing encoded tokens.
+    pub fn encode_bytes(&self, bytes: &[u8]) -> Vec<u32> {
+        self.bpe_base.encode_bytes(bytes).unwrap_or_else(|_| self.bpe_base.encode_single_piece(bytes))
     }
 
-    /// Decode the given tokens into text and offsets.
+    /// Sanitizes possibly invalid UTF-8 bytes by the handler and encodes the result
+    /// ignoring the special tokens, so scraped text with mojibake never fails to encode.
     ///
-    /// This method takes an array of encoded tokens and returns the decoded text along with the
-    /// corresponding offset positions of each token.
+    /// `DecodeErrorHandler::Strict` doesn't sanitize anything. The bytes are encoded like
+    /// `encode_bytes` instead, keeping the invalid bytes as the byte tokens.
     ///
     /// # Arguments
     ///
-    /// * `tokens` - An slice of u32 representing the encoded tokens.
+    /// * `bytes` - The bytes to be encoded.
+    /// * `handler` - How to sanitize the invalid bytes.
     ///
     /// # Returns
     ///
-    /// A `CounterResult` containing a tuple `(String, Vec<usize>)` representing the decoded text and
-    /// the offset positions.
+    /// A vector of `u32` values representing encoded tokens.
+    pub fn encode_lossy_bytes(&self, bytes: &[u8], handler: DecodeErrorHandler) -> Vec<u32> {
+        match (handler, decode_utf8(bytes, handler)) {
+            (DecodeErrorHandler::Strict, _) | (_, Err(_)) => self.encode_bytes(bytes),
+            (_, Ok(text)) => self.encode_ordinary(&text),
+        }
+    }
+
+    /// Encodes UTF-16 text ignoring the special tokens, for the callers which hold
+    /// the text as UTF-16 code units (e.g. Windows APIs or JavaScript bindings).
     ///
-    /// # Errors
+    /// # Arguments
     ///
-    /// Returns a `CounterError::ByteDecodeError` if the byte decoding fails.
-    pub fn decode_with_offsets(self, tokens: &[u32]) -> CounterResult<(String, Vec<usize>)> {
-        let token_bytes = self.decode_tokens_bytes(tokens)?;
+    /// * `units` - The UTF-16 code units of the text to be encoded.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the vector of `u32` values representing encoded tokens.
+    /// Returns a `CounterError::ByteDecodeError` if the units contain an unpaired surrogate.
+    pub fn encode_ordinary_utf16(&self, units: &[u16]) -> CounterResult<Vec<u32>> {
+        let text = String::from_utf16(units).map_err(|e| CounterError::ByteDecodeError(e.to_string()))?;
 
-        let mut text_len = 0;
-        let mut offset: Vec<usize> = Vec::new();
+        Ok(self.encode_ordinary(&text))
+    }
 
-        for token in &token_bytes {
-            offset.push(max(0, text_len - (
-                match 0x80 <= token[0] && token[0] < 0xC0 {
-                    true => 1,
-                    false => 0,
-            })));
-            text_len += token.iter().filter(|byte| !(0x80 <= **byte && **byte < 0xC0)).count();
-        }
+    // ===================
+    // Counting
+    // ===================
 
-        let bytes = token_bytes.iter().flatten().cloned().collect::<Vec<_>>();
-        let text = from_utf8(&bytes).map_err(|e| CounterError::ByteDecodeError(e.to_string()))?;
+    /// Counts the tokens of ordinary text without building the tokens vector.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be counted.
+    ///
+    /// # Returns
+    ///
+    /// The number of tokens, which is equal to `encode_ordinary(text).len()`.
+    pub fn count_ordinary(&self, text: &str) -> usize {
+        self.bpe_base.count_ordinary(text).unwrap_or_else(|_| self.encode_unsplit(text).len())
+    }
 
-        Ok((text.to_string(), offset))
+    /// Counts the tokens of each ordinary text without building the tokens vectors.
+    ///
+    /// # Arguments
+    ///
+    /// * `texts` - The texts to be counted.
+    ///
+    /// # Returns
+    ///
+    /// The numbers of the tokens in the same order as `texts`.
+    pub fn count_batch(&self, texts: &[&str]) -> Vec<usize> {
+        texts.iter().map(|text| self.count_ordinary(text)).collect()
     }
 
-    /// Decodes a batch of tokens into a vector of strings.
+    /// Counts the tokens of each ordinary text in parallel like `count_batch`,
+    /// e.g. for a service counting the tokens of thousands of prompts.
     ///
     /// # Arguments
     ///
-    /// * `batch` - A slice of vectors containing the tokens to decode.
-    /// * `errors` - The error handling strategy of decode failure.
+    /// * `texts` - The texts to be counted.
     ///
     /// # Returns
     ///
-    /// Returns the decoded strings as a `CounterResult<Vec<String>>`. If successful, the `Ok` variant
-    /// contains the decoded strings. If an error occurs during decoding, the `Err` variant contains an
-    /// error message.
-    pub fn decode_batch(&self,
-                        batch: &[Vec<u32>],
-                        errors: DecodeErrorHandler
-    ) -> CounterResult<Vec<String>> {
-        let mut res_str = Vec::new();
+    /// The numbers of the tokens in the same order as `texts`.
+    pub fn count_batch_parallel(&self, texts: &[&str]) -> Vec<usize> {
+        texts.par_iter().map(|text| self.count_ordinary(text)).collect()
+    }
 
-        for token in batch {
-            res_str.push(self.decode(token, errors)?);
-        }
+    /// Checks whether the text encodes to exactly one token by `encode_ordinary`.
+    ///
+    /// This is stricter than `encode_single_token` succeeding: the text must be a token of
+    /// the vocabulary and also be produced as it is by the pre-tokenization and BPE.
+    /// The special tokens are treated as ordinary text, so they are never single tokens here.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be checked.
+    ///
+    /// # Returns
+    ///
+    /// `true` if `encode_ordinary(text)` has exactly one token.
+    pub fn is_single_token(&self, text: &str) -> bool {
+        self.count_ordinary(text) == 1
+    }
 
-        Ok(res_str)
+    /// Counts the tokens of the given text using the specified allowed and disallowed special
+    /// characters without building the tokens vector.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be counted.
+    /// * `allowed_special` - The allowed special characters to include in the encoding.
+    /// * `disallowed_special` - The disallowed special characters to exclude from the encoding.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that contains the number of tokens on success,
+    /// or an error message on failure on `CounterError`.
+    pub fn count(&self,
+                 text: &str,
+                 allowed_special: Specials<'_>,
+                 disallowed_special: Specials<'_>
+    ) -> CounterResult<usize> {
+        let allowed_special =
+            self.validation_specials(text,
+                                     allowed_special.clone(),
+                                     disallowed_special)?;
+
+        self.bpe_base.count(text, allowed_special)
     }
 
-    /// Decodes a slice of tokens vectors into corresponding bytes vector.
+    /// Counts the distinct tokens in the ordinary text, e.g. for the vocabulary coverage of a dataset.
     ///
     /// # Arguments
     ///
-    /// * `batch` - A slice of tokens vectors.
+    /// * `text` - The text to be counted.
     ///
     /// # Returns
     ///
-    /// A vector of bytes, where each byte sequence represents the decoded version of a tokenized sequence.
-    pub fn decode_bytes_batch(&self, batch: &[Vec<u32>]) -> Vec<Vec<u8>> {
-        let mut res_bytes = Vec::new();
+    /// The number of the distinct token ids.
+    pub fn unique_token_count(&self, text: &str) -> usize {
+        self.encode_ordinary(text)
+            .into_iter()
+            .collect::<HashSet<_>>()
+            .len()
+    }
 
-        for token in batch {
-            res_bytes.push(self.decode_bytes(token));
+    /// Counts the occurrences of each token in the ordinary text.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be counted.
+    ///
+    /// # Returns
+    ///
+    /// A `HashMap` mapping each token id to the number of its occurrences.
+    pub fn token_frequencies(&self, text: &str) -> HashMap<u32, usize> {
+        let mut frequencies = HashMap::new();
+        for token in self.encode_ordinary(text) {
+            *frequencies.entry(token).or_insert(0) += 1;
         }
 
-        res_bytes
+        frequencies
     }
 
     // ===================
-    // Miscellaneous
+    // Decoding
     // ===================
 
-    /// Returns the list of all token byte values.
-    pub fn token_bytes_values(&self) -> Vec<Vec<u8>> {
-        self.bpe_base.token_byte_values()
+    /// Decode to vector of bytes from a given token.
+    ///
+    /// This is the unchecked fast path and panics if a token is unknown.
+    /// Use `decode_bytes_checked` for the tokens from an untrusted source.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - A slice of u32 values representing the tokens.
+    ///
+    /// # Returns
+    ///
+    /// Returns a bytes as vector of u8.
+    pub fn decode_bytes(&self, token: &[u32]) -> Vec<u8> {
+        self.bpe_base.decode_bytes(token)
     }
 
-    /// Returns the end-of-text token.
+    /// Decode to vector of bytes from a given token, validating all the tokens up front.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - A slice of u32 values representing the tokens.
     ///
     /// # Returns
     ///
-    /// The end-of-text token as an unsigned 32-bit integer.
-    pub fn end_of_text_token(&self) -> u32 {
-        self.special_token["<|endoftext|>"]
+    /// Returns a `CounterResult` containing the decoded bytes,
+    /// or `CounterError::KeyError` with the first unknown token id.
+    pub fn decode_bytes_checked(&self, token: &[u32]) -> CounterResult<Vec<u8>> {
+        self.bpe_base.decode_bytes_checked(token)
     }
 
-    /// All special tokens set
-    pub fn special_tokens_set(&self) -> HashSet<&str> {
-        self.special_token.keys().map(|key| key.as_str()).collect::<HashSet<_>>()
+    /// Counts the bytes which the tokens decode to without building the buffer,
+    /// e.g. for the length budget calculations.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - A slice of u32 values representing the tokens.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` containing the number of the decoded bytes,
+    /// or `CounterError::KeyError` with the first unknown token id.
+    pub fn decoded_byte_len(&self, tokens: &[u32]) -> CounterResult<usize> {
+        tokens
+            .iter()
+            .map(|token| self.bpe_base.decode_single_token_bytes_ref(*token).map(|bytes| bytes.len()))
+            .sum()
     }
 
-    /// For backwards compatibility.
-    pub fn n_vocab(&self) -> u32 {
-        self.max_token_value + 1
+    /// Returns the byte length of each token without building the buffer,
+    /// e.g. for rendering the token boundaries in a UI.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - A slice of u32 values representing the tokens.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` containing the byte lengths in the same order as `tokens`,
+    /// or `CounterError::KeyError` with the first unknown token id.
+    pub fn token_byte_lengths(&self, tokens: &[u32]) -> CounterResult<Vec<usize>> {
+        tokens
+            .iter()
+            .map(|token| self.bpe_base.decode_single_token_bytes_ref(*token).map(|bytes| bytes.len()))
+            .collect()
     }
 
-    fn validation_specials(&'a self,
-                           text: &str,
-                           allowed_special: Specials<'a>,
-                           disallowed_special: Specials<'a>
-    ) -> CounterResult<HashSet<&'a str>> {
-        let allowed_special = match allowed_special {
-            Specials::All => self.special_tokens_set(),
-            Specials::Collection(allowed_specials) => {
-                allowed_specials
-                    .iter()
-                    .map(|special| *special)
-                    .collect::<HashSet<_>>()
-            }
-        };
-
-        let disallowed_special = match disallowed_special {
-            Specials::All => {
-                self.special_tokens_set()
-                    .difference(&allowed_special)
-                    .cloned()
-                    .collect::<HashSet<_>>()
-            }
-            Specials::Collection(disallowed_specials) => {
-                disallowed_specials
-                    .iter()
-                    .map(|special| *special)
-                    .collect::<HashSet<_>>()
-            }
-        };
-
-        if !disallowed_special.is_empty() {
-            let regex = special_token_regex(disallowed_special)?;
-            if let Some(match_value) = regex.find(text) {
-                return Err(
-                    CounterError::ValueError(
-                        format!(
-                            "Encountered text corresponding to disallowed special token {}.\n \
-                                    If you want this text to be encoded as a special token, \
-                                    pass the token as 'allowed_special'. \
-                                    If you want to encode this as normal text, \
-                                    disable the check for this token by passing \
-                                    a disallowed specials set removing this token. \
-                                    To disable this check for all tokens, \
-                                    `Specials::Collection(&Vec::new())` as `disallowed_special`",
-                            match_value.as_str()
-                        )
-                    ))
-            }
-        }
-        Ok(allowed_special)
+    /// Decodes tokens and appends the bytes into the given buffer.
+    ///
+    /// Reusing the buffer avoids the allocation per call in a hot loop.
+    /// The existing contents of the buffer are kept, so clear it beforehand if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - A slice of u32 values representing the tokens.
+    /// * `out` - The buffer to which the decoded bytes are appended.
+    pub fn decode_bytes_into(&self, token: &[u32], out: &mut Vec<u8>) {
+        self.bpe_base.decode_bytes_into(token, out)
     }
-}
 
-impl <'a> TryFrom<OpenAIInput> for OpenAI {
-    type Error = CounterError;
+    /// Decodes a token into a string.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token to decode, represented as a slice of `u32` values.
+    /// * `errors` - The error handling strategy when decoding fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CounterError` if decoding fails and the error handling strategy is set to `Strict`.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` containing the decoded string on success,
+    /// or the decoded replacement string based on the error handling strategy.
+    pub fn decode(&self, token: &[u32], errors: DecodeErrorHandler) -> CounterResult<String> {
+        let bytes = self.bpe_base.decode_bytes(token);
 
-    fn try_from(value: OpenAIInput) -> Result<OpenAI, Self::Error> {
-        Self::new(
-            value.name,
-            value.pattern,
-            value.merge_able_ranks,
-            value.special_tokens,
-            value.explicit_n_vocab,
-        )
+        decode_with_handler(bytes, errors)
     }
-}
 
-fn special_token_regex(tokens: HashSet<&str>) -> CounterResult<Regex> {
-    let regex_text = tokens
+    /// Decodes tokens and writes the text into the writer without allocating a `String`,
+    /// e.g. for the CLI tools dumping the decoded text to stdout.
+    ///
+    /// The invalid bytes are processed by the error handler before writing. For `Strict`,
+    /// nothing is written if the bytes are not valid UTF-8.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - The tokens to decode.
+    /// * `writer` - The sink of the decoded text.
+    /// * `errors` - The error handling strategy when decoding fails.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` which is `CounterError::ByteDecodeError` if decoding fails with `Strict`,
+    /// or `CounterError::IOError` if writing fails.
+    pub fn decode_to_writer<W: Write>(&self,
+                                      tokens: &[u32],
+                                      writer: &mut W,
+                                      errors: DecodeErrorHandler
+    ) -> CounterResult<()> {
+        let bytes = self.bpe_base.decode_bytes(tokens);
+        let text = decode_utf8(&bytes, errors)?;
+
+        writer.write_all(text.as_bytes()).map_err(|e| CounterError::IOError(e.to_string()))
+    }
+
+    /// Decodes tokens into a string, replacing every invalid byte sequence with the given string.
+    ///
+    /// This is the same as `DecodeErrorHandler::Replace` except for the replacement,
+    /// e.g. "?" or "[?]" instead of "\u{FFFD}".
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The tokens to decode, represented as a slice of `u32` values.
+    /// * `replacement` - The string inserted for each invalid byte sequence.
+    ///
+    /// # Returns
+    ///
+    /// Returns the decoded string.
+    pub fn decode_replace_with(&self, token: &[u32], replacement: &str) -> String {
+        let bytes = self.bpe_base.decode_bytes(token);
+
+        match String::from_utf8(bytes) {
+            Ok(decoded_str) => decoded_str,
+            Err(e) => from_utf8_or(e.as_bytes(), replacement).into_owned(),
+        }
+    }
+
+    /// Decodes a single token into a string.
+    ///
+    /// The bytes of a single token can be a part of a multibyte UTF-8 character,
+    /// so the result for such token depends on the error handling strategy.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token to decode.
+    /// * `errors` - The error handling strategy when decoding fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CounterError::KeyError` if the token is unknown, and a `CounterError::ByteDecodeError`
+    /// if decoding fails and the error handling strategy is set to `Strict`.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` containing the decoded string on success,
+    /// or the decoded replacement string based on the error handling strategy.
+    pub fn decode_single(&self, token: u32, errors: DecodeErrorHandler) -> CounterResult<String> {
+        let bytes = self.bpe_base.decode_single_token_bytes(token)?;
+
+        decode_with_handler(bytes, errors)
+    }
+
+    /// Decodes tokens into a string, rendering or omitting the special tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The tokens to decode, represented as a slice of `u32` values.
+    /// * `render_specials` - Whether the special tokens are rendered as their markers like `<|endoftext|>`.
+    ///   If `false`, the special tokens are omitted from the output.
+    /// * `errors` - The error handling strategy when decoding fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CounterError` if decoding fails and the error handling strategy is set to `Strict`.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` containing the decoded string on success,
+    /// or the decoded replacement string based on the error handling strategy.
+    pub fn decode_with_specials(&self,
+                                token: &[u32],
+                                render_specials: bool,
+                                errors: DecodeErrorHandler
+    ) -> CounterResult<String> {
+        if render_specials {
+            return self.decode(token, errors)
+        }
+
+        self.decode(&self.strip_special(token), errors)
+    }
+
+    /// Decodes the tokens wrapped by `encode_with_bos_eos`, ignoring the wrapper tokens.
+    ///
+    /// Only the first token equal to `bos` and the last token equal to `eos` are ignored,
+    /// so the same tokens in the middle are decoded as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - The tokens to decode.
+    /// * `bos` - The begin-of-sequence token to be ignored at the head.
+    /// * `eos` - The end-of-sequence token to be ignored at the tail.
+    /// * `errors` - The error handling strategy when decoding fails.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` containing the decoded string,
+    /// or `CounterError::ByteDecodeError` if decoding fails with `Strict`.
+    pub fn decode_without_bos_eos(&self,
+                                  tokens: &[u32],
+                                  bos: Option<u32>,
+                                  eos: Option<u32>,
+                                  errors: DecodeErrorHandler
+    ) -> CounterResult<String> {
+        let mut tokens = tokens;
+        if let (Some(bos), [first, rest @ ..]) = (bos, tokens) {
+            if *first == bos {
+                tokens = rest;
+            }
+        }
+        if let (Some(eos), [rest @ .., last]) = (eos, tokens) {
+            if *last == eos {
+                tokens = rest;
+            }
+        }
+
+        self.decode(tokens, errors)
+    }
+
+    /// Decodes a single token into a vector of bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token to decode.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the decoded vector of bytes.
+    pub fn decode_single_tokens_bytes(self, token: u32) -> CounterResult<Vec<u8>> {
+        self.bpe_base.decode_single_token_bytes(token)
+    }
+
+    /// Decode a slice of tokens into a vector of byte vectors.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - A slice of u32 tokens representing the encoded tokens.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing vector of the decoded byte vectors,
+    /// or an error if the decoding fails.
+    pub fn decode_tokens_bytes(self, tokens: &[u32]) -> CounterResult<Vec<Vec<u8>>> {
+        let mut res = Vec::with_capacity(tokens.len());
+
+        for token in tokens {
+            res.push(self.bpe_base.decode_single_token_bytes(*token)?);
+        }
+
+        Ok(res)
+    }
+
+    /// Decode a slice of tokens into the byte slices borrowed from the vocabulary.
+    ///
+    /// Unlike `decode_tokens_bytes`, no bytes are copied per token, so this is preferable
+    /// for long sequences as long as the result doesn't need to outlive the tokenizer.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - A slice of u32 tokens representing the encoded tokens.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing vector of the byte slices of the tokens.
+    /// Returns a `CounterError::KeyError` if a token is unknown.
+    pub fn decode_tokens_bytes_ref(&self, tokens: &[u32]) -> CounterResult<Vec<&[u8]>> {
+        tokens
+            .iter()
+            .map(|token| self.bpe_base.decode_single_token_bytes_ref(*token))
+            .collect()
+    }
+
+    /// Decode the given tokens into text and offsets.
+    ///
+    /// This method takes an array of encoded tokens and returns the decoded text along with the
+    /// corresponding offset positions of each token.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - An slice of u32 representing the encoded tokens.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing a tuple `(String, Vec<usize>)` representing the decoded text and
+    /// the offset positions.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CounterError::ByteDecodeError` if the byte decoding fails.
+    pub fn decode_with_offsets(self, tokens: &[u32]) -> CounterResult<(String, Vec<usize>)> {
+        let token_bytes = self.decode_tokens_bytes(tokens)?;
+
+        let mut text_len = 0;
+        let mut offset: Vec<usize> = Vec::new();
+
+        for token in &token_bytes {
+            offset.push(max(0, text_len - (
+                match 0x80 <= token[0] && token[0] < 0xC0 {
+                    true => 1,
+                    false => 0,
+            })));
+            text_len += token.iter().filter(|byte| !(0x80 <= **byte && **byte < 0xC0)).count();
+        }
+
+        let bytes = token_bytes.iter().flatten().cloned().collect::<Vec<_>>();
+        let text = from_utf8(&bytes).map_err(|e| CounterError::ByteDecodeError(e.to_string()))?;
+
+        Ok((text.to_string(), offset))
+    }
+
+    /// Decodes the given tokens into text and the start char index of each token,
+    /// e.g. for the UI code indexing the strings by Unicode scalar values like Python.
+    ///
+    /// A token beginning in the middle of a character (e.g. the last byte of an emoji)
+    /// is assigned the char index of the character it falls within.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - An slice of u32 representing the encoded tokens.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing a tuple `(String, Vec<usize>)` representing the decoded text and
+    /// the char offsets in the same order as `tokens`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CounterError::KeyError` if a token is unknown,
+    /// or `CounterError::ByteDecodeError` if the bytes are not valid UTF-8.
+    pub fn decode_with_char_offsets(&self, tokens: &[u32]) -> CounterResult<(String, Vec<usize>)> {
+        let token_bytes = self.decode_tokens_bytes_ref(tokens)?;
+        let bytes = token_bytes.concat();
+        let text = String::from_utf8(bytes).map_err(|e| CounterError::ByteDecodeError(e.to_string()))?;
+
+        let is_continuation = |byte: u8| (0x80..0xC0).contains(&byte);
+        let mut n_chars: usize = 0;
+        let mut offsets = Vec::with_capacity(tokens.len());
+        for bytes in token_bytes {
+            match bytes.first() {
+                Some(byte) if is_continuation(*byte) => offsets.push(n_chars.saturating_sub(1)),
+                _ => offsets.push(n_chars),
+            }
+            n_chars += bytes.iter().filter(|byte| !is_continuation(**byte)).count();
+        }
+
+        Ok((text, offsets))
+    }
+
+    /// Decodes a batch of tokens into a vector of strings.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - A slice of vectors containing the tokens to decode.
+    /// * `errors` - The error handling strategy of decode failure.
+    ///
+    /// # Returns
+    ///
+    /// Returns the decoded strings as a `CounterResult<Vec<String>>`. If successful, the `Ok` variant
+    /// contains the decoded strings. If an error occurs during decoding, the `Err` variant contains an
+    /// error message. For `ByteDecodeError`, the message starts with the failed index like `batch[42]`
+    /// followed by the tokens producing the invalid bytes.
+    pub fn decode_batch(&self,
+                        batch: &[Vec<u32>],
+                        errors: DecodeErrorHandler
+    ) -> CounterResult<Vec<String>> {
+        let mut res_str = Vec::new();
+
+        for (idx, token) in batch.iter().enumerate() {
+            res_str.push(self.decode_batch_element(idx, token, errors)?);
+        }
+
+        Ok(res_str)
+    }
+
+    /// Decodes a batch of tokens into a vector of strings in parallel using rayon.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - A slice of vectors containing the tokens to decode.
+    /// * `errors` - The error handling strategy of decode failure.
+    ///
+    /// # Returns
+    ///
+    /// Returns the decoded strings in the same order as `batch`, or the error in the same form
+    /// as `decode_batch`. The decoding is short-circuited on an error, and if several elements
+    /// fail, which one is reported is not deterministic.
+    pub fn decode_batch_parallel(&self,
+                                 batch: &[Vec<u32>],
+                                 errors: DecodeErrorHandler
+    ) -> CounterResult<Vec<String>> {
+        batch
+            .par_iter()
+            .enumerate()
+            .map(|(idx, token)| self.decode_batch_element(idx, token, errors))
+            .collect()
+    }
+
+    fn decode_batch_element(&self,
+                            idx: usize,
+                            token: &[u32],
+                            errors: DecodeErrorHandler
+    ) -> CounterResult<String> {
+        self.decode(token, errors).map_err(|e| match e {
+            CounterError::ByteDecodeError(e) => CounterError::ByteDecodeError(
+                format!("batch[{}] at tokens {:?}: {}", idx, self.invalid_utf8_tokens(token), e)),
+            e => e,
+        })
+    }
+
+    /// Decodes a slice of tokens vectors into corresponding bytes vector.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - A slice of tokens vectors.
+    ///
+    /// # Returns
+    ///
+    /// A vector of bytes, where each byte sequence represents the decoded version of a tokenized sequence.
+    pub fn decode_bytes_batch(&self, batch: &[Vec<u32>]) -> Vec<Vec<u8>> {
+        let mut res_bytes = Vec::new();
+
+        for token in batch {
+            res_bytes.push(self.decode_bytes(token));
+        }
+
+        res_bytes
+    }
+
+    /// Decodes a slice of tokens vectors into a single concatenated buffer, which avoids
+    /// the allocation per element when the result is written to a file or a socket.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - A slice of tokens vectors.
+    ///
+    /// # Returns
+    ///
+    /// A tuple of the concatenated bytes and the length of each decoded element,
+    /// so the element `i` starts at the sum of the lengths before it.
+    pub fn decode_bytes_batch_flat(&self, batch: &[Vec<u32>]) -> (Vec<u8>, Vec<usize>) {
+        let mut res_bytes = Vec::new();
+        let mut lengths = Vec::with_capacity(batch.len());
+
+        for token in batch {
+            let start = res_bytes.len();
+            self.decode_bytes_into(token, &mut res_bytes);
+            lengths.push(res_bytes.len() - start);
+        }
+
+        (res_bytes, lengths)
+    }
+
+    // ===================
+    // Miscellaneous
+    // ===================
+
+    /// Returns the name of the encoding like `cl100k_base`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the pattern string used for the pre-tokenization.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Returns the list of all token byte values.
+    pub fn token_bytes_values(&self) -> Vec<Vec<u8>> {
+        self.bpe_base.token_byte_values()
+    }
+
+    /// Returns the bytes of the all tokens excluding the special tokens without copying them.
+    ///
+    /// The slice is sorted in ascending order of the bytes, so the prefix queries can be done
+    /// by the binary search like `partition_point`.
+    pub fn sorted_token_bytes(&self) -> &[Vec<u8>] {
+        self.bpe_base.sorted_token_bytes()
+    }
+
+    /// Iterates over the vocabulary as pairs of the token id and its bytes in arbitrary order.
+    ///
+    /// # Arguments
+    ///
+    /// * `include_special` - If true, the special tokens are also yielded.
+    pub fn iter_vocab(&self, include_special: bool) -> impl Iterator<Item = (u32, &[u8])> + '_ {
+        self.bpe_base.iter_vocab(include_special)
+    }
+
+    /// Returns the number of the tokens in the vocabulary excluding the special tokens.
+    pub fn vocab_len(&self) -> usize {
+        self.bpe_base.vocab_len()
+    }
+
+    /// Checks whether the merge ranks have a token for every single byte,
+    /// which a byte-level BPE needs to encode and decode arbitrary bytes.
+    ///
+    /// `with_byte_coverage_check` rejects the tokenizer failing this.
+    ///
+    /// # Returns
+    ///
+    /// `true` if all the 256 single bytes are in the merge ranks.
+    pub fn has_full_byte_coverage(&self) -> bool {
+        missing_single_byte(&self.merge_able_ranks).is_none()
+    }
+
+    /// Rejects the tokenizer whose merge ranks miss a single byte,
+    /// since the text containing the byte can't be encoded.
+    ///
+    /// The built-in encodings of `Models` are checked by this.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` that resolves to the tokenizer itself if `has_full_byte_coverage` holds,
+    /// or `CounterError::ValueError` naming the first missing byte.
+    pub fn with_byte_coverage_check(self) -> CounterResult<OpenAI> {
+        match missing_single_byte(&self.merge_able_ranks) {
+            Some(byte) => Err(CounterError::ValueError(format!(
+                "merge ranks don't have the single byte {:#04x}, so arbitrary bytes can't be encoded.", byte))),
+            None => Ok(self),
+        }
+    }
+
+    /// Checks whether the pre-tokenization pattern is the one of the encoding which the merge ranks
+    /// come from, e.g. to catch the cl100k ranks combined with the gpt2 pattern by mistake,
+    /// which doesn't fail but tokenizes the text silently wrong.
+    ///
+    /// The pattern splits a probe text mixing the contractions, the digits and the newlines,
+    /// and the number of the pieces is compared to the one expected for the encoding.
+    /// Only the built-in encodings loaded by `Models` have the expectation.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the number of the pieces differs from the expectation,
+    /// `true` if it matches or the tokenizer has no expectation.
+    pub fn sanity_check_pattern(&self) -> bool {
+        match self.expected_probe_pieces {
+            Some(expected) => self.pretokenize(PATTERN_PROBE).len() == expected,
+            None => true,
+        }
+    }
+
+    pub(crate) fn with_expected_probe_pieces(mut self, expected_probe_pieces: Option<usize>) -> Self {
+        self.expected_probe_pieces = expected_probe_pieces;
+        self
+    }
+
+    /// Checks whether the token is a special token.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token to be checked.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the token is one of the special tokens.
+    pub fn is_special(&self, token: u32) -> bool {
+        self.special_token.values().any(|special| *special == token)
+    }
+
+    /// Classifies the token into a single byte, a merged token or a special token.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token to be classified.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the `TokenKind` of the token, or `None` if the token is unknown.
+    pub fn token_kind(&self, token: u32) -> Option<TokenKind> {
+        if self.is_special(token) {
+            return Some(TokenKind::Special)
+        }
+
+        self.bpe_base
+            .decode_single_token_bytes_ref(token)
+            .ok()
+            .map(|bytes| if bytes.len() == 1 { TokenKind::Byte } else { TokenKind::Merge })
+    }
+
+    /// Removes all the special tokens from the tokens, e.g. the control tokens in the model output.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - The tokens to be filtered.
+    ///
+    /// # Returns
+    ///
+    /// A vector of the tokens except the special tokens, keeping their order.
+    pub fn strip_special(&self, tokens: &[u32]) -> Vec<u32> {
+        tokens
+            .iter()
+            .filter(|token| !self.is_special(**token))
+            .cloned()
+            .collect()
+    }
+
+    /// Truncates the tokens at the first end-of-text token.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - The tokens to be truncated.
+    ///
+    /// # Returns
+    ///
+    /// The tokens before the first `<|endoftext|>`, or all the tokens if there is none
+    /// or the tokenizer has no end-of-text token.
+    pub fn trim_after_eot<'t>(&self, tokens: &'t [u32]) -> &'t [u32] {
+        let end = self.end_of_text_token()
+            .and_then(|eot| tokens.iter().position(|token| *token == eot))
+            .unwrap_or(tokens.len());
+
+        &tokens[..end]
+    }
+
+    /// Returns the aggregate statistics of the merge tokens and the special tokens.
+    ///
+    /// # Returns
+    ///
+    /// The `VocabStats` of the vocabulary.
+    pub fn vocab_stats(&self) -> VocabStats {
+        let ids = self.merge_able_ranks
+            .values()
+            .chain(self.special_token.values())
+            .cloned()
+            .collect::<HashSet<_>>();
+        let n_ids = self.merge_able_ranks.len() + self.special_token.len();
+
+        // The merge ranks are never empty since `new` rejects it.
+        let min_id = ids.iter().min().cloned().unwrap_or(0);
+        let max_id = ids.iter().max().cloned().unwrap_or(0);
+
+        VocabStats {
+            n_merge_tokens: self.merge_able_ranks.len(),
+            n_special_tokens: self.special_token.len(),
+            min_id,
+            max_id,
+            is_contiguous: ids.len() == n_ids && (max_id - min_id) as usize + 1 == n_ids,
+        }
+    }
+
+    /// Returns the end-of-text token.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the end-of-text token, or `None` if the tokenizer has no
+    /// `<|endoftext|>` like some user-defined tokenizers. The built-in encodings always have it.
+    pub fn end_of_text_token(&self) -> Option<u32> {
+        self.special_token.get("<|endoftext|>").cloned()
+    }
+
+    /// Finds the special tokens literally present in the text.
+    ///
+    /// This helps to decide the `allowed_special` and `disallowed_special` before encoding.
+    /// The adjacent specials are found separately and, if a special is a prefix of another one,
+    /// the longer one is preferred.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be inspected.
+    ///
+    /// # Returns
+    ///
+    /// A vector of the found special tokens paired with their byte offsets, in order of appearance.
+    pub fn find_special_tokens<'b>(&self, text: &'b str) -> Vec<(&'b str, usize)> {
+        self.bpe_base.find_special_tokens(text)
+    }
+
+    /// All special tokens set
+    pub fn special_tokens_set(&self) -> HashSet<&str> {
+        self.special_token.keys().map(|key| key.as_str()).collect::<HashSet<_>>()
+    }
+
+    /// For backwards compatibility.
+    ///
+    /// The number is returned as `u64` since it is `u32::MAX + 1` when the max token id is `u32::MAX`.
+    pub fn n_vocab(&self) -> u64 {
+        self.max_token_value as u64 + 1
+    }
+
+    /// Returns the tokens producing the first invalid UTF-8 sequence in the decoded bytes.
+    fn invalid_utf8_tokens<'b>(&self, tokens: &'b [u32]) -> &'b [u32] {
+        let bytes = self.bpe_base.decode_bytes(tokens);
+        let e = match from_utf8(&bytes) {
+            Ok(_) => return &[],
+            Err(e) => e,
+        };
+        let invalid_end = e.valid_up_to() + e.error_len().unwrap_or(bytes.len() - e.valid_up_to());
+
+        let mut start = tokens.len();
+        let mut end = tokens.len();
+        let mut token_end = 0;
+        for (idx, token) in tokens.iter().enumerate() {
+            token_end += self.bpe_base.decode_bytes(&[*token]).len();
+            if start == tokens.len() && token_end > e.valid_up_to() {
+                start = idx;
+            }
+            if token_end >= invalid_end {
+                end = idx + 1;
+                break
+            }
+        }
+
+        &tokens[start..end]
+    }
+
+    /// Encodes the whole text as one BPE piece without the pre-tokenization regex,
+    /// which is the fallback of the infallible encoding methods when the regex fails.
+    fn encode_unsplit(&self, text: &str) -> Vec<u32> {
+        self.bpe_base.encode_single_piece(text.as_bytes())
+    }
+
+    fn validation_specials(&'a self,
+                           text: &str,
+                           allowed_special: Specials<'a>,
+                           disallowed_special: Specials<'a>
+    ) -> CounterResult<HashSet<&'a str>> {
+        let allowed_special = match allowed_special {
+            Specials::All => self.special_tokens_set(),
+            Specials::Collection(allowed_specials) => {
+                allowed_specials
+                    .iter()
+                    .map(|special| *special)
+                    .collect::<HashSet<_>>()
+            }
+            // The owned set can't be borrowed beyond this call, so the allowed ones are borrowed
+            // from the specials of the tokenizer. The others can't be encoded as specials anyway.
+            Specials::Owned(allowed_specials) => {
+                self.special_token
+                    .keys()
+                    .filter(|special| allowed_specials.contains(*special))
+                    .map(|special| special.as_str())
+                    .collect::<HashSet<_>>()
+            }
+        };
+
+        let disallowed_special = match &disallowed_special {
+            Specials::All => {
+                self.special_tokens_set()
+                    .difference(&allowed_special)
+                    .cloned()
+                    .collect::<HashSet<_>>()
+            }
+            Specials::Collection(disallowed_specials) => {
+                disallowed_specials
+                    .iter()
+                    .map(|special| *special)
+                    .collect::<HashSet<_>>()
+            }
+            Specials::Owned(disallowed_specials) => {
+                disallowed_specials
+                    .iter()
+                    .map(|special| special.as_str())
+                    .collect::<HashSet<_>>()
+            }
+        };
+
+        if !disallowed_special.is_empty() {
+            // Disallowing all the specials is the default of the encoding, so the regex compiled
+            // with the BPE core is reused instead of being rebuilt on every call.
+            let compiled;
+            let cached = if disallowed_special.len() == self.special_token.len()
+                && disallowed_special.iter().all(|special| self.special_token.contains_key(*special)) {
+                self.bpe_base.special_regex()
+            } else {
+                None
+            };
+            let regex = match cached {
+                Some(regex) => regex,
+                None => {
+                    compiled = special_token_regex(disallowed_special)?;
+                    &compiled
+                }
+            };
+            if let Some(match_value) = regex.find(text) {
+                return Err(
+                    CounterError::ValueError(
+                        format!(
+                            "Encountered text corresponding to disallowed special token {}.\n \
+                                    If you want this text to be encoded as a special token, \
+                                    pass the token as 'allowed_special'. \
+                                    If you want to encode this as normal text, \
+                                    disable the check for this token by passing \
+                                    a disallowed specials set removing this token. \
+                                    To disable this check for all tokens, \
+                                    `Specials::Collection(&Vec::new())` as `disallowed_special`",
+                            match_value.as_str()
+                        )
+                    ))
+            }
+        }
+        Ok(allowed_special)
+    }
+}
+
+impl <'a> TryFrom<OpenAIInput> for OpenAI {
+    type Error = CounterError;
+
+    fn try_from(value: OpenAIInput) -> Result<OpenAI, Self::Error> {
+        let tokenizer = Self::new(
+            value.name,
+            value.pattern,
+            value.merge_able_ranks,
+            value.special_tokens,
+            value.explicit_n_vocab,
+        )?;
+
+        let tokenizer = tokenizer.with_expected_probe_pieces(value.expected_probe_pieces);
+        if value.require_full_byte_coverage {
+            return tokenizer.with_byte_coverage_check()
+        }
+
+        Ok(tokenizer)
+    }
+}
+
+/// Two tokenizers are equal when they have the same name, pattern, merge ranks and special tokens.
+/// Note that comparing the merge ranks is O(n) in the vocabulary size.
+impl PartialEq for OpenAI {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name &&
+            self.pattern == other.pattern &&
+            self.special_token == other.special_token &&
+            self.merge_able_ranks == other.merge_able_ranks
+    }
+}
+
+impl Eq for OpenAI {}
+
+/// Prints only the name and the vocabulary size since the merge ranks are gigantic.
+impl Debug for OpenAI {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpenAI")
+            .field("name", &self.name)
+            .field("n_vocab", &self.n_vocab())
+            .finish()
+    }
+}
+
+/// Validates that the merge ranks and the special token ids form a contiguous id range
+/// starting from 0 with no duplicates.
+///
+/// Note that some encodings (e.g. cl100k_base) have the intentional gaps between the merge ranks
+/// and the special tokens, so `OpenAI::new` only validates when `explicit_n_vocab` is specified.
+///
+/// # Arguments
+///
+/// * `merge_able_ranks` - A `HashMap` mapping byte sequences to merge ranks.
+/// * `special_tokens` - A `HashMap` mapping special tokens to ids.
+///
+/// # Returns
+///
+/// Returns `CounterError::ValueError` describing the first duplicate or gap.
+pub fn validate_ranks(merge_able_ranks: &HashMap<Vec<u8>, u32>,
+                      special_tokens: &HashMap<String, u32>
+) -> CounterResult<()> {
+    let mut ids = merge_able_ranks
+        .values()
+        .chain(special_tokens.values())
+        .cloned()
+        .collect::<Vec<_>>();
+    ids.sort_unstable();
+
+    for (&id, expected) in ids.iter().zip(0u32..) {
+        if id < expected {
+            return Err(CounterError::ValueError(format!("token id {} is duplicated.", id)))
+        }
+        if id > expected {
+            return Err(CounterError::ValueError(format!(
+                "token ids are not contiguous: id {} is missing but {} exists.", expected, id)))
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that every special token has its own id which is not one of the merge ranks.
+///
+/// # Returns
+///
+/// A `CounterResult` which is `CounterError::ValueError` if two special tokens have the same id
+/// or a special token collides with a merge rank.
+pub(crate) fn check_special_ids<R, S>(merge_able_ranks: &HashMap<Vec<u8>, u32, R>,
+                                      special_tokens: &HashMap<String, u32, S>
+) -> CounterResult<()> {
+    let mut special_ids = HashMap::with_capacity(special_tokens.len());
+    for (token, id) in special_tokens {
+        if let Some(other) = special_ids.insert(*id, token) {
+            return Err(CounterError::ValueError(format!(
+                "special tokens '{}' and '{}' have the same id {}.", other, token, id)))
+        }
+    }
+
+    if let Some((bytes, id)) = merge_able_ranks
         .iter()
-        .map(|token| token.to_string())
+        .find(|(_, id)| special_ids.contains_key(id)) {
+        return Err(CounterError::ValueError(format!(
+            "special token '{}' collides with the merge rank {} of {:?}.",
+            special_ids[id], id, bytes)))
+    }
+
+    Ok(())
+}
+
+/// Returns the first single byte which doesn't have its own token.
+fn missing_single_byte(merge_able_ranks: &FxHashMap<Vec<u8>, u32>) -> Option<u8> {
+    (0..=255_u8).find(|byte| !merge_able_ranks.contains_key([*byte].as_slice()))
+}
+
+/// Decodes the bytes into a string using the error handling strategy.
+///
+/// The decoded string is borrowed from `bytes` when they are valid UTF-8, so combined with
+/// `OpenAI::decode_bytes` it avoids the extra allocation of the string in hot loops.
+/// Only when the bytes have invalid sequences, the handled string is allocated.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes to decode.
+/// * `errors` - The error handling strategy when decoding fails.
+///
+/// # Returns
+///
+/// Returns a `CounterResult` containing the decoded string on success,
+/// or `CounterError::ByteDecodeError` if decoding fails with `Strict`.
+pub fn decode_utf8(bytes: &[u8], errors: DecodeErrorHandler) -> CounterResult<Cow<'_, str>> {
+    match from_utf8(bytes) {
+        Ok(decoded_str) => Ok(Cow::Borrowed(decoded_str)),
+        Err(e) => {
+            match errors {
+                DecodeErrorHandler::Strict => Err(CounterError::ByteDecodeError(e.to_string())),
+                DecodeErrorHandler::Replace => Ok(from_utf8_replace(bytes)),
+                DecodeErrorHandler::Ignore => Ok(from_utf8_ignore(bytes)),
+                DecodeErrorHandler::BackSlashReplace => Ok(from_utf8_backslash(bytes)),
+            }
+        }
+    }
+}
+
+/// Decodes the owned bytes reusing their buffer when they are valid UTF-8.
+fn decode_with_handler(bytes: Vec<u8>, errors: DecodeErrorHandler) -> CounterResult<String> {
+    match String::from_utf8(bytes) {
+        Ok(decoded_str) => Ok(decoded_str),
+        Err(e) => Ok(decode_utf8(e.as_bytes(), errors)?.into_owned()),
+    }
+}
+
+fn serialize_ranks<S: Serializer>(ranks: &HashMap<Vec<u8>, u32>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(ranks.iter().map(|(bytes, rank)| (BASE64_STANDARD.encode(bytes), rank)))
+}
+
+fn deserialize_ranks<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<Vec<u8>, u32>, D::Error> {
+    HashMap::<String, u32>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(encoded, rank)| {
+            BASE64_STANDARD
+                .decode(&encoded)
+                .map(|bytes| (bytes, rank))
+                .map_err(|e| serde::de::Error::custom(format!("invalid base64 '{}': {}", encoded, e)))
+        })
+        .collect()
+}
+
+fn special_token_regex(tokens: HashSet<&str>) -> CounterResult<Regex> {
+    let regex_text = tokens
+        .iter()
+        .map(|token| regex::escape(token))
         .collect::<Vec<_>>()
         .join("|");
 
-    Regex::new(regex_text.as_str()).map_err(|e| CounterError::RegexError(e.to_string()))
+    Regex::new(regex_text.as_str()).map_err(|e| CounterError::RegexError(e.to_string()))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use std::thread;
+    use crate::errors::CounterError;
+    use crate::tokenizer::openai::{decode_utf8, validate_ranks, DecodeErrorHandler, DisallowedAction, Models, NormalizationForm, OpenAI, SingleInput, Specials, TokenKind, TruncationSide, VocabStats};
+    use crate::tokenizer::openai::builder::OpenAIBuilder;
+    use crate::tokenizer::openai::testing::{model_pattern, model_ranks, test_input, test_ranks, test_tokenizer, TEST_PATTERN};
+
+    const TEXTS: [&str; 6] = [
+        "",
+        "hello world",
+        "hello the world, the thing is nothing.",
+        "caf\u{e9} \u{1F600}\u{1F600}!",
+        "  multiple   spaces\n\tand tabs  ",
+        "hello<|endoftext|>world<|endoftext|>",
+    ];
+
+    #[test]
+    fn test_count_ordinary() {
+        let tokenizer = test_tokenizer();
+        for text in TEXTS {
+            assert_eq!(tokenizer.count_ordinary(text), tokenizer.encode_ordinary(text).len());
+        }
+    }
+
+    #[test]
+    fn test_count() {
+        let tokenizer = test_tokenizer();
+        for text in TEXTS {
+            assert_eq!(
+                tokenizer.count(text, Specials::All, Specials::All).unwrap(),
+                tokenizer.encode(text, Specials::All, Specials::All).unwrap().len());
+        }
+        assert!(tokenizer.count("<|endoftext|>", Specials::Collection(&[]), Specials::All).is_err());
+    }
+
+    fn _assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        _assert_send_sync::<OpenAI>();
+    }
+
+    #[test]
+    fn test_encode_from_multiple_threads() {
+        let tokenizer = Arc::new(test_tokenizer());
+        let text = "hello the world, the thing is nothing.";
+        let expected = tokenizer.encode_ordinary(text);
+
+        let handles = (0..8)
+            .map(|_| {
+                let tokenizer = Arc::clone(&tokenizer);
+                thread::spawn(move || {
+                    (0..100).map(|_| tokenizer.encode_ordinary(text)).collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            for tokens in handle.join().unwrap() {
+                assert_eq!(tokens, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_iter_vocab() {
+        let tokenizer = test_tokenizer();
+
+        assert_eq!(tokenizer.iter_vocab(true).count(), tokenizer.n_vocab() as usize);
+        assert_eq!(tokenizer.iter_vocab(false).count(), tokenizer.vocab_len());
+        assert!(tokenizer
+            .iter_vocab(false)
+            .all(|(token, bytes)| tokenizer.encode_single_token(SingleInput::Bytes(bytes)).unwrap() == token));
+    }
+
+    #[test]
+    fn test_decode_batch_failed_index() {
+        let tokenizer = test_tokenizer();
+        let batch = vec![
+            tokenizer.encode_ordinary("hello"),
+            vec![104, 0xff, 105],
+            tokenizer.encode_ordinary("world"),
+        ];
+
+        match tokenizer.decode_batch(&batch, DecodeErrorHandler::Strict) {
+            Err(CounterError::ByteDecodeError(e)) => assert!(e.starts_with("batch[1] at tokens [255]"), "{}", e),
+            _ => panic!("decode_batch should fail with ByteDecodeError"),
+        }
+        assert!(tokenizer.decode_batch(&batch, DecodeErrorHandler::Replace).is_ok());
+    }
+
+    #[test]
+    fn test_decode_single() {
+        let tokenizer = test_tokenizer();
+        let hello = tokenizer.encode_single_token(SingleInput::String("hello")).unwrap();
+        // The first two bytes of the 4 bytes emoji.
+        let partial = tokenizer.encode_single_token(SingleInput::Bytes(b"\xf0\x9f")).unwrap();
+
+        for errors in [DecodeErrorHandler::Strict, DecodeErrorHandler::Replace,
+            DecodeErrorHandler::Ignore, DecodeErrorHandler::BackSlashReplace] {
+            assert_eq!(tokenizer.decode_single(hello, errors).unwrap(), "hello");
+        }
+
+        assert!(matches!(
+            tokenizer.decode_single(partial, DecodeErrorHandler::Strict),
+            Err(CounterError::ByteDecodeError(_))));
+        assert_eq!(tokenizer.decode_single(partial, DecodeErrorHandler::Replace).unwrap(), "\u{FFFD}");
+        assert_eq!(tokenizer.decode_single(partial, DecodeErrorHandler::Ignore).unwrap(), "");
+        assert_eq!(tokenizer.decode_single(partial, DecodeErrorHandler::BackSlashReplace).unwrap(), "\\xf0\\x9f");
+        assert!(matches!(
+            tokenizer.decode_single(tokenizer.n_vocab() as u32, DecodeErrorHandler::Replace),
+            Err(CounterError::KeyError(_))));
+    }
+
+    #[test]
+    fn test_validate_ranks() {
+        let gapped = [(b"a".to_vec(), 0), (b"b".to_vec(), 1), (b"c".to_vec(), 3)]
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>();
+
+        assert_eq!(
+            validate_ranks(&gapped, &HashMap::new()),
+            Err(CounterError::ValueError("token ids are not contiguous: id 2 is missing but 3 exists.".to_string())));
+        assert!(OpenAI::new(
+            "gapped".to_string(), TEST_PATTERN.to_string(), gapped.clone(), HashMap::new(), Some(3)).is_err());
+
+        let specials = [("<|endoftext|>".to_string(), 2)].iter().cloned().collect::<HashMap<_, _>>();
+        assert!(validate_ranks(&gapped, &specials).is_ok());
+
+        let duplicated = [("<|endoftext|>".to_string(), 1)].iter().cloned().collect::<HashMap<_, _>>();
+        assert_eq!(
+            validate_ranks(&gapped, &duplicated),
+            Err(CounterError::ValueError("token id 1 is duplicated.".to_string())));
+
+        assert!(validate_ranks(&test_ranks(), &HashMap::new()).is_ok());
+    }
+
+    #[test]
+    fn test_encode_with_offsets() {
+        let tokenizer = test_tokenizer();
+        let text = "hello the world, caf\u{e9} hellp \u{1F600}!!\n";
+        let (tokens, offsets) = tokenizer.encode_with_offsets(text);
+
+        assert_eq!(tokens, tokenizer.encode_ordinary(text));
+        assert_eq!(tokens.len(), offsets.len());
+        assert_eq!(offsets.first().unwrap().0, 0);
+        assert_eq!(offsets.last().unwrap().1, text.len());
+        assert!(offsets.windows(2).all(|pair| pair[0].1 == pair[1].0));
+
+        let mut reconstructed = Vec::new();
+        for (token, (start, end)) in tokens.iter().zip(&offsets) {
+            let bytes = &text.as_bytes()[*start..*end];
+            assert_eq!(tokenizer.bpe_base.decode_single_token_bytes(*token).unwrap(), bytes);
+            reconstructed.extend_from_slice(bytes);
+        }
+        assert_eq!(reconstructed, text.as_bytes());
+    }
+
+    #[test]
+    fn test_decode_utf8_cow() {
+        let tokenizer = test_tokenizer();
+        let bytes = tokenizer.decode_bytes(&tokenizer.encode_ordinary("hello world"));
+        assert!(matches!(decode_utf8(&bytes, DecodeErrorHandler::Strict), Ok(Cow::Borrowed("hello world"))));
+
+        let invalid = b"hello\xffworld";
+        for (errors, expected) in [
+            (DecodeErrorHandler::Replace, "hello\u{FFFD}world"),
+            (DecodeErrorHandler::Ignore, "helloworld"),
+            (DecodeErrorHandler::BackSlashReplace, "hello\\xffworld")] {
+            match decode_utf8(invalid, errors) {
+                Ok(Cow::Owned(decoded)) => assert_eq!(decoded, expected),
+                _ => panic!("invalid bytes should be decoded into an owned string"),
+            }
+        }
+        assert!(decode_utf8(invalid, DecodeErrorHandler::Strict).is_err());
+    }
+
+    #[test]
+    fn test_encode_ordinary_with_pattern() {
+        let tokenizer = test_tokenizer();
+        let text = "hello the world, the thing is nothing.";
+
+        let same_pattern = fancy_regex::Regex::new(TEST_PATTERN).unwrap();
+        assert_eq!(
+            tokenizer.encode_ordinary_with_pattern(text, &same_pattern).unwrap(),
+            tokenizer.encode_ordinary(text));
+
+        // Splitting every character disables all the merges.
+        let char_pattern = fancy_regex::Regex::new(r"(?s:.)").unwrap();
+        let tokens = tokenizer.encode_ordinary_with_pattern(text, &char_pattern).unwrap();
+        assert_eq!(tokens, text.bytes().map(|byte| byte as u32).collect::<Vec<_>>());
+
+        // The look-ahead of the GPT-2 pattern leaves the last space to the following word.
+        let look_ahead = fancy_regex::Regex::new(r" ?\p{L}+|\s+(?!\S)|\s+").unwrap();
+        assert_eq!(tokenizer.encode_ordinary_with_pattern("hello  world", &look_ahead).unwrap(), vec![259, 32, 264]);
+
+        let catastrophic = fancy_regex::RegexBuilder::new(r"(a+)+(?=b)")
+            .backtrack_limit(10)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            tokenizer.encode_ordinary_with_pattern("aaaaaaaaaaaaaaaaaaaa", &catastrophic),
+            Err(CounterError::RegexError(_))));
+    }
+
+    #[test]
+    fn test_encode_piece() {
+        let tokenizer = test_tokenizer();
+
+        assert!(tokenizer.encode_single_token(SingleInput::Bytes(b"hi")).is_err());
+        assert_eq!(tokenizer.encode_piece(b"hi"), vec![b'h' as u32, b'i' as u32]);
+        assert_eq!(
+            tokenizer.encode_piece(b"hello"),
+            vec![tokenizer.encode_single_token(SingleInput::Bytes(b"hello")).unwrap()]);
+        assert_eq!(tokenizer.encode_piece(b""), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_accessors() {
+        let tokenizer = test_tokenizer();
+
+        assert_eq!(tokenizer.name(), "test_base");
+        assert_eq!(tokenizer.pattern(), TEST_PATTERN);
+        assert_eq!(format!("{:?}", tokenizer), "OpenAI { name: \"test_base\", n_vocab: 275 }");
+
+        let tokenizer = OpenAI::from_model_ranks(Models::CL100KBase, model_ranks(Models::CL100KBase)).unwrap();
+        assert_eq!(tokenizer.name(), "cl100k_base");
+        assert_eq!(tokenizer.pattern(), model_pattern(Models::CL100KBase));
+        assert!(tokenizer.pattern().contains(r"\p{N}{1,3}"));
+        assert_eq!(format!("{:?}", tokenizer), "OpenAI { name: \"cl100k_base\", n_vocab: 100277 }");
+    }
+
+    #[test]
+    fn test_eq() {
+        assert_eq!(test_tokenizer(), test_tokenizer());
+
+        let mut input = test_input();
+        input.name = "other_base".to_string();
+        assert_ne!(test_tokenizer(), OpenAI::try_from(input).unwrap());
+
+        let mut input = test_input();
+        input.pattern = r"\S+|\s+".to_string();
+        assert_ne!(test_tokenizer(), OpenAI::try_from(input).unwrap());
+
+        let mut input = test_input();
+        input.merge_able_ranks.remove(b"hello".as_slice());
+        input.explicit_n_vocab = None;
+        assert_ne!(test_tokenizer(), OpenAI::try_from(input).unwrap());
+    }
+
+    #[test]
+    fn test_decode_batch_parallel() {
+        let tokenizer = test_tokenizer();
+        let batch = (0..500)
+            .map(|idx| tokenizer.encode_ordinary(&format!("hello {} the world \u{1F600}", idx)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            tokenizer.decode_batch_parallel(&batch, DecodeErrorHandler::Strict).unwrap(),
+            tokenizer.decode_batch(&batch, DecodeErrorHandler::Strict).unwrap());
+
+        let mut invalid = batch.clone();
+        invalid[42] = vec![0xff];
+        assert_eq!(
+            tokenizer.decode_batch_parallel(&invalid, DecodeErrorHandler::Strict),
+            tokenizer.decode_batch(&invalid, DecodeErrorHandler::Strict));
+    }
+
+    #[test]
+    fn test_encode_truncated() {
+        let tokenizer = test_tokenizer();
+        let text = "hello the world, the thing is nothing.";
+        let tokens = tokenizer.encode_ordinary(text);
+
+        assert_eq!(tokenizer.encode_truncated(text, 5, TruncationSide::Right).unwrap(), tokens[..5]);
+        assert_eq!(tokenizer.encode_truncated(text, 5, TruncationSide::Left).unwrap(), tokens[tokens.len() - 5..]);
+        for side in [TruncationSide::Left, TruncationSide::Right] {
+            assert_eq!(tokenizer.encode_truncated(text, tokens.len() + 10, side).unwrap(), tokens);
+            assert!(tokenizer.encode_truncated(text, 0, side).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_chunk_by_tokens() {
+        let tokenizer = test_tokenizer();
+        // 10 tokens of "hello" and " world"
+        let text = "hello world".repeat(5);
+        let hello_world = ["hello", " world"];
+
+        let chunks = tokenizer.chunk_by_tokens(&text, 4, 1).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0], hello_world.repeat(2).concat());
+        assert_eq!(chunks[1], [" world", "hello", " world", "hello"].concat());
+        assert_eq!(chunks[2], ["hello", " world", "hello", " world"].concat());
+
+        assert_eq!(tokenizer.chunk_by_tokens(&text, 20, 5).unwrap(), vec![text.clone()]);
+        assert!(tokenizer.chunk_by_tokens("", 4, 1).unwrap().is_empty());
+        assert!(tokenizer.chunk_by_tokens(&text, 4, 4).is_err());
+    }
+
+    #[test]
+    fn test_encode_bytes() {
+        let tokenizer = test_tokenizer();
+
+        assert_eq!(tokenizer.encode_bytes(b"hello world"), tokenizer.encode_ordinary("hello world"));
+        assert_eq!(tokenizer.encode_bytes(b""), Vec::<u32>::new());
+        // The invalid tail alone isn't dropped.
+        assert_eq!(tokenizer.encode_bytes(b"\xff"), vec![0xff]);
+        // "hello" and the truncated emoji are re-merged as one piece.
+        assert_eq!(tokenizer.encode_bytes(b"hello \xf0\x9f\x98"), vec![259, 32, 273]);
+        assert_eq!(tokenizer.encode_bytes(b"hello\xf0\x9f"), vec![259, 272]);
+    }
+
+    #[test]
+    fn test_encode_bytes_round_trip() {
+        let tokenizer = test_tokenizer();
+        let alphabet = b"hello world\n\t'l\xc3\xa9\xf0\x9f\x98\x80\xff\x80<|endoftext|>";
+
+        // xorshift keeps the generated inputs reproducible.
+        let mut state = 0x2545f491u32;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as usize
+        };
+
+        for _ in 0..500 {
+            let len = next() % 24;
+            let bytes = (0..len).map(|_| alphabet[next() % alphabet.len()]).collect::<Vec<_>>();
+            let bytes = if next() % 2 == 0 {
+                bytes
+            } else {
+                (0..len).map(|_| next() as u8).collect()
+            };
+
+            assert_eq!(tokenizer.decode_bytes(&tokenizer.encode_bytes(&bytes)), bytes, "input: {:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_decode_with_specials() {
+        let tokenizer = test_tokenizer();
+        let tokens = tokenizer.encode("hello world<|endoftext|> the", Specials::All, Specials::Collection(&[])).unwrap();
+        assert!(tokens.contains(&tokenizer.end_of_text_token().unwrap()));
+
+        assert_eq!(tokenizer.decode_with_specials(&tokens, true, DecodeErrorHandler::Strict).unwrap(),
+                   "hello world<|endoftext|> the");
+        assert_eq!(tokenizer.decode_with_specials(&tokens, false, DecodeErrorHandler::Strict).unwrap(),
+                   "hello world the");
+    }
+
+    #[test]
+    fn test_decode_replace_with() {
+        let tokenizer = test_tokenizer();
+        let tokens = [259, 0xff, 264, 0xf0, 0x9f];
+
+        assert_eq!(tokenizer.decode_replace_with(&tokens, "[?]"), "hello[?] world[?]");
+        assert_eq!(tokenizer.decode_replace_with(&tokens, "\u{FFFD}"),
+                   tokenizer.decode(&tokens, DecodeErrorHandler::Replace).unwrap());
+        assert_eq!(tokenizer.decode_replace_with(&[259, 264], "?"), "hello world");
+    }
+
+    #[test]
+    fn test_find_special_tokens() {
+        let tokenizer = OpenAIBuilder::from(test_input())
+            .add_special_token("<|end|>", 275)
+            .add_special_token("<|endoftext|><|end|>", 276)
+            .explicit_n_vocab(277)
+            .build()
+            .unwrap();
+        let text = "<|endoftext|>hello <|end|><|end|> world<|endoftext|><|end|>";
+
+        assert_eq!(tokenizer.find_special_tokens(text), vec![
+            ("<|endoftext|>", 0),
+            ("<|end|>", 19),
+            ("<|end|>", 26),
+            ("<|endoftext|><|end|>", 39),
+        ]);
+        assert!(tokenizer.find_special_tokens("hello world").is_empty());
+
+        let without_specials = OpenAI::new(
+            "no_specials".to_string(), TEST_PATTERN.to_string(), test_ranks(), HashMap::new(), None).unwrap();
+        assert!(without_specials.find_special_tokens("hello <|endoftext|>").is_empty());
+    }
+
+    #[test]
+    fn test_serialized_round_trip() {
+        let input = test_input();
+        let json = serde_json::to_vec(&input).unwrap();
+        let value = serde_json::from_slice::<serde_json::Value>(&json).unwrap();
+        // " world" is kept as base64.
+        assert_eq!(value["merge_able_ranks"]["IHdvcmxk"], 264);
+
+        let tokenizer = OpenAI::from_serialized(&json).unwrap();
+        let expected = test_tokenizer();
+        assert_eq!(tokenizer, expected);
+        for text in TEXTS {
+            assert_eq!(tokenizer.encode_ordinary(text), expected.encode_ordinary(text));
+        }
+
+        let reloaded = OpenAI::from_serialized(&expected.to_serialized().unwrap()).unwrap();
+        assert_eq!(reloaded, expected);
+
+        assert!(OpenAI::from_serialized(b"{}").is_err());
+        assert!(OpenAI::from_serialized(br#"{"name": "x", "pattern": "x", "merge_able_ranks": {"!!": 0},
+            "special_tokens": {}, "explicit_n_vocab": null}"#).is_err());
+    }
+
+    #[test]
+    fn test_from_model_ranks() {
+        let ranks = (0..=255_u8)
+            .map(|byte| (vec![byte], byte as u32))
+            .chain((256..50256u32).map(|rank| ([&[0xff, 0xfe], rank.to_be_bytes().as_slice()].concat(), rank)))
+            .collect::<HashMap<_, _>>();
+
+        let tokenizer = OpenAI::from_model_ranks(Models::R50KBase, ranks.clone()).unwrap();
+        assert_eq!(tokenizer.name(), "r50k_base");
+        assert_eq!(tokenizer.n_vocab(), 50257);
+        assert_eq!(tokenizer.encode("<|endoftext|>", Specials::All, Specials::All).unwrap(), vec![50256]);
+        assert_eq!(tokenizer.decode_bytes(&tokenizer.encode_ordinary("hello world")), b"hello world");
+
+        let err = OpenAI::from_model_ranks(Models::CL100KBase, ranks).unwrap_err();
+        assert!(matches!(err, CounterError::ValueError(_)));
+    }
+
+    #[test]
+    fn test_encode_between_specials() {
+        let tokenizer = test_tokenizer();
+        let text = "hello<|endoftext|> world<|endoftext|>the";
+        let eot = tokenizer.end_of_text_token().unwrap();
+
+        // The ordinary text before, between and after the specials is encoded exactly once.
+        let expected = vec![259, eot, 264, eot, 268];
+        assert_eq!(tokenizer.encode(text, Specials::All, Specials::All).unwrap(), expected);
+        assert_eq!(tokenizer.count(text, Specials::All, Specials::All).unwrap(), expected.len());
+        // The last piece "the" is unstable and excluded from the stable tokens.
+        let (stable, completions) = tokenizer.encode_with_unstable(text, Specials::All, Specials::All).unwrap();
+        assert_eq!(stable, expected[..4]);
+        assert!(completions.contains(&vec![268]));
+        assert_eq!(tokenizer.encode("<|endoftext|><|endoftext|>", Specials::All, Specials::All).unwrap(),
+                   vec![eot, eot]);
+    }
+
+    #[test]
+    fn test_vocab_stats() {
+        assert_eq!(test_tokenizer().vocab_stats(), VocabStats {
+            n_merge_tokens: 274,
+            n_special_tokens: 1,
+            min_id: 0,
+            max_id: 274,
+            is_contiguous: true,
+        });
+
+        // The special tokens of cl100k_base leave a gap after <|fim_suffix|>.
+        let tokenizer = OpenAI::from_model_ranks(Models::CL100KBase, model_ranks(Models::CL100KBase)).unwrap();
+        assert_eq!(tokenizer.vocab_stats(), VocabStats {
+            n_merge_tokens: 100256,
+            n_special_tokens: 5,
+            min_id: 0,
+            max_id: 100276,
+            is_contiguous: false,
+        });
+    }
+
+    #[test]
+    fn test_clone_shares_tables() {
+        let tokenizer = test_tokenizer();
+        let cloned = tokenizer.clone();
+
+        assert!(Arc::ptr_eq(&tokenizer.merge_able_ranks, &cloned.merge_able_ranks));
+        // The original, the clone and the BPE tables of both share one merge table.
+        assert_eq!(Arc::strong_count(&tokenizer.merge_able_ranks), 4);
+        assert_eq!(cloned, tokenizer);
+        assert_eq!(cloned.encode_ordinary("hello world"), tokenizer.encode_ordinary("hello world"));
+    }
+
+    #[test]
+    fn test_encode_decode_into() {
+        let tokenizer = test_tokenizer();
+        let mut tokens = Vec::new();
+        let mut bytes = Vec::new();
+
+        for text in TEXTS {
+            tokens.clear();
+            tokenizer.encode_ordinary_into(text, &mut tokens);
+            assert_eq!(tokens, tokenizer.encode_ordinary(text));
+
+            bytes.clear();
+            tokenizer.decode_bytes_into(&tokens, &mut bytes);
+            assert_eq!(bytes, text.as_bytes());
+        }
+
+        // The contents are appended to the existing ones.
+        tokenizer.encode_ordinary_into("hello", &mut tokens);
+        assert_eq!(tokens[tokens.len() - 1], 259);
+        tokenizer.decode_bytes_into(&[259], &mut bytes);
+        assert!(bytes.ends_with(b"hello"));
+    }
+
+    #[test]
+    fn test_token_frequencies() {
+        let tokenizer = test_tokenizer();
+        let text = "hello world hello world hello";
+
+        // " hello" is split into " " and "hello".
+        assert_eq!(tokenizer.count_ordinary(text), 7);
+        assert_eq!(tokenizer.unique_token_count(text), 3);
+        assert!(tokenizer.unique_token_count(text) < tokenizer.count_ordinary(text));
+
+        let frequencies = tokenizer.token_frequencies(text);
+        assert_eq!(frequencies.len(), 3);
+        assert_eq!(frequencies[&259], 3);
+        assert_eq!(frequencies[&264], 2);
+        assert_eq!(frequencies[&(b' ' as u32)], 2);
+        assert!(tokenizer.token_frequencies("").is_empty());
+    }
+
+    #[test]
+    fn test_duplicated_id_error() {
+        let mut ranks = test_ranks();
+        ranks.insert(b"wor".to_vec(), 259);
+
+        let result = OpenAI::new(
+            "duplicated".to_string(), TEST_PATTERN.to_string(), ranks, HashMap::new(), None);
+        match result {
+            Err(CounterError::ValueError(message)) => assert!(message.starts_with("token id 259 is duplicated")),
+            _ => panic!("the duplicated token id must be an error."),
+        }
+
+        let mut input = test_input();
+        input.explicit_n_vocab = Some(300);
+        assert!(OpenAI::try_from(input).is_err());
+    }
+
+    #[test]
+    fn test_with_additional_specials() {
+        let extra = [("<|im_start|>".to_string(), 275), ("<|im_end|>".to_string(), 276)]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let tokenizer = test_tokenizer().with_additional_specials(extra).unwrap();
+
+        let text = "<|im_start|>user\nhello world<|im_end|>";
+        let tokens = tokenizer.encode(text, Specials::All, Specials::All).unwrap();
+        assert_eq!(tokens[0], 275);
+        assert_eq!(tokens[tokens.len() - 1], 276);
+        assert_eq!(tokenizer.decode(&tokens, DecodeErrorHandler::Strict).unwrap(), text);
+        assert_eq!(tokenizer.n_vocab(), 277);
+        assert_eq!(tokenizer.end_of_text_token(), Some(274));
+
+        // The id collides with the merge rank of "hello".
+        let collided = [("<|im_start|>".to_string(), 259)].into_iter().collect::<HashMap<_, _>>();
+        assert!(test_tokenizer().with_additional_specials(collided).is_err());
+        let collided = [("<|im_start|>".to_string(), 274)].into_iter().collect::<HashMap<_, _>>();
+        assert!(test_tokenizer().with_additional_specials(collided).is_err());
+    }
+
+    #[test]
+    fn test_encode_batch_iter() {
+        let tokenizer = test_tokenizer();
+        let texts = TEXTS.iter().map(|text| text.to_string()).collect::<Vec<String>>();
+
+        let expected = tokenizer.encode_batch(&TEXTS, Specials::All, Specials::All).unwrap();
+        assert_eq!(tokenizer.encode_batch_iter(texts, Specials::All, Specials::All).unwrap(), expected);
+        assert_eq!(tokenizer.encode_batch_iter(TEXTS.iter(), Specials::All, Specials::All).unwrap(), expected);
+        assert!(tokenizer.encode_batch_iter(["<|endoftext|>"], Specials::Collection(&[]), Specials::All).is_err());
+    }
+
+    #[test]
+    fn test_decode_bytes_checked() {
+        let tokenizer = test_tokenizer();
+        let eot = tokenizer.end_of_text_token().unwrap();
+
+        assert_eq!(tokenizer.decode_bytes_checked(&[259, 264, eot]).unwrap(), b"hello world<|endoftext|>");
+
+        let unknown = tokenizer.n_vocab() as u32 + 1000;
+        assert_eq!(tokenizer.decode_bytes_checked(&[259, unknown, 264]),
+                   Err(CounterError::KeyError(unknown.to_string())));
+    }
+
+    #[test]
+    fn test_encode_ordinary_utf16() {
+        let tokenizer = test_tokenizer();
+        let text = "hello world \u{1F600}";
+        let units = text.encode_utf16().collect::<Vec<_>>();
+
+        assert_eq!(tokenizer.encode_ordinary_utf16(&units).unwrap(), tokenizer.encode_ordinary(text));
+        assert!(matches!(
+            tokenizer.encode_ordinary_utf16(&[0x68, 0xd83d]),
+            Err(CounterError::ByteDecodeError(_))
+        ));
+    }
+
+    #[test]
+    fn test_longest_token_prefix() {
+        let tokenizer = test_tokenizer();
+
+        assert_eq!(tokenizer.longest_token_prefix("hello world"), Some((259, 5)));
+        assert_eq!(tokenizer.longest_token_prefix(" worlds"), Some((264, 6)));
+        assert_eq!(tokenizer.longest_token_prefix("hel"), Some((256, 2)));
+        assert_eq!(tokenizer.longest_token_prefix("x"), Some((b'x' as u32, 1)));
+        assert_eq!(tokenizer.longest_token_prefix("\u{1F600}"), Some((273, 3)));
+        assert_eq!(tokenizer.longest_token_prefix(""), None);
+    }
+
+    #[test]
+    fn test_decode_tokens_bytes_ref() {
+        let tokenizer = test_tokenizer();
+        let tokens = tokenizer.encode("hello world<|endoftext|>\u{1F600}", Specials::All, Specials::All).unwrap();
+
+        let borrowed = tokenizer.decode_tokens_bytes_ref(&tokens).unwrap();
+        let owned = tokenizer.clone().decode_tokens_bytes(&tokens).unwrap();
+        assert_eq!(borrowed, owned.iter().map(|bytes| bytes.as_slice()).collect::<Vec<_>>());
+
+        assert!(matches!(tokenizer.decode_tokens_bytes_ref(&[0, 100_000]), Err(CounterError::KeyError(_))));
+    }
+
+    #[test]
+    fn test_token_kind() {
+        let tokenizer = test_tokenizer();
+
+        assert!(tokenizer.is_special(274));
+        assert!(!tokenizer.is_special(259));
+        assert_eq!(tokenizer.token_kind(274), Some(TokenKind::Special));
+        assert_eq!(tokenizer.token_kind(b'h' as u32), Some(TokenKind::Byte));
+        assert_eq!(tokenizer.token_kind(259), Some(TokenKind::Merge));
+        assert_eq!(tokenizer.token_kind(275), None);
+    }
+
+    #[test]
+    fn test_encode_lossy_bytes() {
+        let tokenizer = test_tokenizer();
+        let bytes = b"hello\xff world";
+
+        assert_eq!(tokenizer.encode_lossy_bytes(bytes, DecodeErrorHandler::Ignore),
+                   tokenizer.encode_ordinary("hello world"));
+        assert_eq!(tokenizer.encode_lossy_bytes(bytes, DecodeErrorHandler::Replace),
+                   tokenizer.encode_ordinary("hello\u{FFFD} world"));
+        assert_eq!(tokenizer.encode_lossy_bytes(bytes, DecodeErrorHandler::BackSlashReplace),
+                   tokenizer.encode_ordinary("hello\\xff world"));
+        assert_eq!(tokenizer.encode_lossy_bytes(bytes, DecodeErrorHandler::Strict), tokenizer.encode_bytes(bytes));
+        assert_eq!(tokenizer.encode_lossy_bytes(b"hello world", DecodeErrorHandler::Strict),
+                   tokenizer.encode_ordinary("hello world"));
+    }
+
+    #[test]
+    fn test_encode_single_piece() {
+        let tokenizer = test_tokenizer();
+
+        assert!(tokenizer.encode_single_token(SingleInput::Bytes(b"hello world")).is_err());
+        assert_eq!(tokenizer.encode_single_piece(SingleInput::Bytes(b"hello world")), vec![259, 264]);
+        assert_eq!(tokenizer.encode_single_piece(SingleInput::String("hello")), vec![259]);
+        assert_eq!(tokenizer.encode_single_piece(SingleInput::String("")), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_decode_bytes_batch_flat() {
+        let tokenizer = test_tokenizer();
+        let batch = vec![
+            tokenizer.encode_ordinary("hello world"),
+            vec![],
+            tokenizer.encode("the<|endoftext|>", Specials::All, Specials::All).unwrap(),
+            vec![0xf0, 0x9f],
+        ];
+
+        let (bytes, lengths) = tokenizer.decode_bytes_batch_flat(&batch);
+        assert_eq!(lengths.len(), batch.len());
+
+        let mut rest = bytes.as_slice();
+        let mut reconstructed = Vec::new();
+        for length in lengths {
+            let (element, tail) = rest.split_at(length);
+            reconstructed.push(element.to_vec());
+            rest = tail;
+        }
+        assert!(rest.is_empty());
+        assert_eq!(reconstructed, tokenizer.decode_bytes_batch(&batch));
+    }
+
+    #[test]
+    fn test_sorted_token_bytes() {
+        let tokenizer = test_tokenizer();
+        let sorted = tokenizer.sorted_token_bytes();
+
+        assert_eq!(sorted.len(), tokenizer.vocab_len());
+        assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let start = sorted.partition_point(|bytes| bytes.as_slice() < b"he".as_slice());
+        assert_eq!(&sorted[start..start + 2], [b"he".to_vec(), b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn test_is_single_token() {
+        let tokenizer = test_tokenizer();
+
+        assert!(tokenizer.is_single_token("hello"));
+        assert!(tokenizer.is_single_token(" world"));
+        assert!(tokenizer.is_single_token("x"));
+        assert!(!tokenizer.is_single_token("hello world"));
+        assert!(!tokenizer.is_single_token(""));
+        assert!(!tokenizer.is_single_token("<|endoftext|>"));
+    }
+
+    #[test]
+    fn test_end_of_text_token_missing() {
+        let mut input = test_input();
+        input.special_tokens = HashMap::from([("<|fim_prefix|>".to_string(), 274)]);
+        let tokenizer = OpenAI::try_from(input).unwrap();
+
+        assert_eq!(tokenizer.end_of_text_token(), None);
+        assert_eq!(test_tokenizer().end_of_text_token(), Some(274));
+    }
+
+    #[test]
+    fn test_strip_special() {
+        let mut input = test_input();
+        input.special_tokens.insert("<|fim_prefix|>".to_string(), 275);
+        input.explicit_n_vocab = None;
+        let tokenizer = OpenAI::try_from(input).unwrap();
+
+        let tokens = tokenizer
+            .encode("<|fim_prefix|>hello<|endoftext|> world<|fim_prefix|>", Specials::All, Specials::All)
+            .unwrap();
+        assert_eq!(tokens, vec![275, 259, 274, 264, 275]);
+        assert_eq!(tokenizer.strip_special(&tokens), vec![259, 264]);
+        assert_eq!(tokenizer.strip_special(&[]), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_trim_after_eot() {
+        let tokenizer = test_tokenizer();
+
+        assert_eq!(tokenizer.trim_after_eot(&[259, 264, 274, 259, 274]), [259, 264]);
+        assert_eq!(tokenizer.trim_after_eot(&[274, 259]), Vec::<u32>::new().as_slice());
+        assert_eq!(tokenizer.trim_after_eot(&[259, 264]), [259, 264]);
+    }
+
+    #[test]
+    fn test_decoded_byte_len() {
+        let tokenizer = test_tokenizer();
+        let tokens = tokenizer.encode("hello world<|endoftext|>\u{1F600}é", Specials::All, Specials::All).unwrap();
+
+        assert_eq!(tokenizer.decoded_byte_len(&tokens).unwrap(), tokenizer.decode_bytes(&tokens).len());
+        assert_eq!(tokenizer.decoded_byte_len(&[]).unwrap(), 0);
+        assert!(matches!(tokenizer.decoded_byte_len(&[259, 100_000]), Err(CounterError::KeyError(_))));
+    }
+
+    #[test]
+    fn test_token_byte_lengths() {
+        let tokenizer = test_tokenizer();
+        let tokens = tokenizer.encode("hello world<|endoftext|>\u{1F600}é", Specials::All, Specials::All).unwrap();
+
+        let lengths = tokenizer.token_byte_lengths(&tokens).unwrap();
+        assert_eq!(lengths.len(), tokens.len());
+        assert_eq!(lengths[..3], [5, 6, 13]);
+        assert_eq!(lengths.iter().sum::<usize>(), tokenizer.decoded_byte_len(&tokens).unwrap());
+        assert!(matches!(tokenizer.token_byte_lengths(&[259, 100_000]), Err(CounterError::KeyError(_))));
+    }
+
+    #[test]
+    fn test_encode_owned_specials() {
+        let tokenizer = test_tokenizer();
+        let text = "hello<|endoftext|>";
+
+        let allowed = ["<|endof", "text|>"].concat();
+        let tokens = tokenizer
+            .encode(text, Specials::Owned(HashSet::from([allowed])), Specials::All)
+            .unwrap();
+        assert_eq!(tokens, vec![259, 274]);
+
+        let disallowed = (0..1).map(|_| "<|endoftext|>".to_string()).collect::<HashSet<_>>();
+        assert!(tokenizer.encode(text, Specials::Collection(&[]), Specials::Owned(disallowed)).is_err());
+        assert_eq!(tokenizer.encode(text, Specials::Owned(HashSet::new()), Specials::Owned(HashSet::new())).unwrap(),
+                   tokenizer.encode_ordinary(text));
+    }
+
+    #[test]
+    fn test_regex_failure() {
+        // The nested quantifier backtracks exponentially before the look-ahead fails.
+        let mut input = test_input();
+        input.pattern = r"(?:l+)+(?=x)|.".to_string();
+        let tokenizer = OpenAI::try_from(input).unwrap();
+        let text = format!("he{}o", "l".repeat(40));
+
+        assert!(matches!(tokenizer.encode(&text, Specials::All, Specials::All), Err(CounterError::RegexError(_))));
+        assert!(tokenizer.count(&text, Specials::All, Specials::All).is_err());
+        assert!(tokenizer.encode_truncated(&text, 3, TruncationSide::Right).is_err());
+        assert!(matches!(tokenizer.try_encode_ordinary(&text), Err(CounterError::RegexError(_))));
+        assert_eq!(tokenizer.try_encode_ordinary("hello").unwrap(), tokenizer.encode_ordinary("hello"));
+        assert_eq!(tokenizer.try_encode_ordinary("").unwrap(), Vec::<u32>::new());
+
+        // The infallible methods encode the whole text as one piece instead of panicking.
+        let tokens = tokenizer.encode_ordinary(&text);
+        assert_eq!(tokens, tokenizer.encode_piece(text.as_bytes()));
+        assert_eq!(tokenizer.decode_bytes(&tokens), text.as_bytes());
+        assert_eq!(tokenizer.count_ordinary(&text), tokens.len());
+        let (offset_tokens, offsets) = tokenizer.encode_with_offsets(&text);
+        assert_eq!(offset_tokens, tokens);
+        assert_eq!(offsets.last().unwrap().1, text.len());
+    }
+
+    #[test]
+    fn test_encode_ordinary_annotated() {
+        let tokenizer = test_tokenizer();
+
+        assert_eq!(tokenizer.encode_ordinary_annotated("hello world"), vec![(259, false), (264, false)]);
+        // The emoji isn't a token as a whole, so it's split into the partial bytes.
+        assert_eq!(tokenizer.encode_ordinary_annotated("hello\u{1F600}"), vec![(259, false), (273, true), (0x80, true)]);
+
+        let text = "the thing \u{1F600}é hello";
+        let annotated = tokenizer.encode_ordinary_annotated(text);
+        assert_eq!(annotated.iter().map(|(token, _)| *token).collect::<Vec<_>>(), tokenizer.encode_ordinary(text));
+    }
+
+    #[test]
+    fn test_debug_enums() {
+        assert_eq!(format!("{:?}", DecodeErrorHandler::Replace), "Replace");
+        assert_eq!(format!("{:?}", Specials::Collection(&["<|endoftext|>"])), r#"Collection(["<|endoftext|>"])"#);
+        assert_eq!(format!("{:?}", Specials::All), "All");
+        assert_eq!(format!("{:?}", SingleInput::String("hello")), r#"String("hello")"#);
+    }
+
+    #[test]
+    fn test_encode_tail() {
+        let tokenizer = test_tokenizer();
+        let text = "hello world the thing \u{1F600}";
+        let tokens = tokenizer.encode_ordinary(text);
+
+        for n in 0..=tokens.len() + 1 {
+            assert_eq!(tokenizer.encode_tail(text, n), tokens[tokens.len().saturating_sub(n)..]);
+        }
+        assert_eq!(tokenizer.encode_tail("", 3), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_with_pattern() {
+        let tokenizer = test_tokenizer();
+        let text = "hello world";
+        assert_eq!(tokenizer.encode_ordinary(text), vec![259, 264]);
+
+        // The space isn't attached to the next word by the whitespace split.
+        let split = tokenizer.clone().with_pattern(r"\S+|\s+").unwrap();
+        assert_eq!(split.pattern(), r"\S+|\s+");
+        assert_eq!(split.encode_ordinary(text), vec![259, b' ' as u32, b'w' as u32, 261, 263]);
+        assert_eq!(split.decode_bytes(&split.encode_ordinary(text)), text.as_bytes());
+
+        assert!(matches!(tokenizer.with_pattern(r"(\S+"), Err(CounterError::RegexError(_))));
+    }
+
+    #[test]
+    fn test_decode_to_writer() {
+        let tokenizer = test_tokenizer();
+        let handlers = [
+            DecodeErrorHandler::Replace,
+            DecodeErrorHandler::Ignore,
+            DecodeErrorHandler::BackSlashReplace,
+        ];
+
+        for tokens in [tokenizer.encode_ordinary("hello world \u{1F600}"), vec![259, 0xff, 264]] {
+            for errors in handlers {
+                let mut buffer = Vec::new();
+                tokenizer.decode_to_writer(&tokens, &mut buffer, errors).unwrap();
+                assert_eq!(buffer, tokenizer.decode(&tokens, errors).unwrap().into_bytes());
+            }
+        }
+
+        let mut buffer = Vec::new();
+        assert!(tokenizer.decode_to_writer(&[259, 0xff], &mut buffer, DecodeErrorHandler::Strict).is_err());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_encode_batch_disallowed_all() {
+        let tokenizer = test_tokenizer();
+        let texts = (0..1000)
+            .map(|i| format!("hello world {} < the thing >", i))
+            .collect::<Vec<_>>();
+
+        for text in &texts {
+            assert_eq!(tokenizer.encode(text, Specials::Collection(&[]), Specials::All).unwrap(),
+                       tokenizer.encode_ordinary(text));
+        }
+
+        // The specials must be still rejected with the cached regex.
+        let text = format!("{}<|endoftext|>", texts[0]);
+        assert!(tokenizer.encode(&text, Specials::Collection(&[]), Specials::All).is_err());
+        assert!(tokenizer.encode(&text, Specials::All, Specials::All).is_ok());
+    }
+
+    #[test]
+    fn test_max_piece_bytes() {
+        let text = "hello".repeat(20000);
+        let tokenizer = test_tokenizer().with_max_piece_bytes(Some(256));
+
+        let tokens = tokenizer.encode_ordinary(&text);
+        assert_eq!(tokenizer.decode(&tokens, DecodeErrorHandler::Strict).unwrap(), text);
+        // Every chunk of 256 bytes is encoded separately.
+        let expected = text
+            .as_bytes()
+            .chunks(256)
+            .flat_map(|chunk| test_tokenizer().encode_ordinary(std::str::from_utf8(chunk).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, expected);
+
+        // The short pieces are not affected.
+        assert_eq!(tokenizer.encode_ordinary("hello world"), test_tokenizer().encode_ordinary("hello world"));
+        // The counting and the offsets split the pieces in the same way.
+        let small = test_tokenizer().with_max_piece_bytes(Some(2));
+        for text in ["hellothe worldly", "hello world \u{1F600}\u{e9}", &"hello".repeat(100)] {
+            let tokens = small.encode_ordinary(text);
+            assert_eq!(small.count_ordinary(text), tokens.len(), "{}", text);
+            assert_eq!(small.count(text, Specials::All, Specials::All).unwrap(), tokens.len(), "{}", text);
+            let (offset_tokens, offsets) = small.encode_with_offsets(text);
+            assert_eq!(offset_tokens, tokens, "{}", text);
+            assert_eq!(offsets.last().unwrap().1, text.len());
+            assert!(offsets.windows(2).all(|pair| pair[0].1 == pair[1].0));
+        }
+        assert_eq!(small.count_batch(&["hellothe worldly"]), vec![small.encode_ordinary("hellothe worldly").len()]);
+
+        // The setting is kept by rebuilding the BPE core.
+        let tokenizer = tokenizer.with_pattern(TEST_PATTERN).unwrap();
+        assert_eq!(tokenizer.encode_ordinary(&text), expected);
+    }
+
+    #[test]
+    fn test_has_full_byte_coverage() {
+        // The coverage of the real cl100k_base vocabulary is asserted by `models::test::test_encoding`,
+        // which downloads it.
+        assert!(test_tokenizer().has_full_byte_coverage());
+
+        let mut ranks = test_ranks();
+        ranks.remove([0xff_u8].as_slice());
+        let tokenizer = OpenAI::new("test_base".to_string(),
+                                    TEST_PATTERN.to_string(),
+                                    ranks.clone(),
+                                    HashMap::new(),
+                                    None).unwrap();
+        assert!(!tokenizer.has_full_byte_coverage());
+
+        assert!(test_tokenizer().with_byte_coverage_check().is_ok());
+        match tokenizer.with_byte_coverage_check() {
+            Err(CounterError::ValueError(message)) => assert!(message.contains("0xff"), "{}", message),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // The explicit vocabulary size validates only the ids, and the coverage is checked on request.
+        let mut ranks = test_ranks();
+        let id = ranks.remove([0xff_u8].as_slice()).unwrap();
+        ranks.insert(b"llll".to_vec(), id);
+        let tokenizer = OpenAI::new("test_base".to_string(), TEST_PATTERN.to_string(), ranks.clone(), HashMap::new(), Some(274)).unwrap();
+        assert!(!tokenizer.has_full_byte_coverage());
+        let builder = OpenAIBuilder::new().name("test_base").pattern(TEST_PATTERN).mergeable_ranks(ranks);
+        assert!(builder.clone().build().is_ok());
+        assert!(builder.require_full_byte_coverage(true).build().is_err());
+
+        // The built-in encodings require the coverage.
+        let mut ranks = model_ranks(Models::R50KBase);
+        let id = ranks.remove([0xff_u8].as_slice()).unwrap();
+        ranks.insert(b"\xfe\xfe".to_vec(), id);
+        assert!(matches!(OpenAI::from_model_ranks(Models::R50KBase, ranks), Err(CounterError::ValueError(_))));
+    }
+
+    #[test]
+    fn test_byte_fallback_report() {
+        let tokenizer = test_tokenizer();
+        assert!(tokenizer.byte_fallback_report("hello world").is_empty());
+
+        // "ü" is not merged unlike "é", so its bytes fall back to the single-byte tokens.
+        let report = tokenizer.byte_fallback_report("hello \u{fc}\u{fc} \u{e9}");
+        assert_eq!(report, HashMap::from([(0xc3, 2), (0xbc, 2), (b' ', 2)]));
+    }
+
+    #[test]
+    fn test_encode_ordinary_normalized() {
+        let tokenizer = test_tokenizer();
+        let nfc = "caf\u{e9}";
+        let nfd = "cafe\u{301}";
+        assert_ne!(tokenizer.encode_ordinary(nfc), tokenizer.encode_ordinary(nfd));
+
+        let tokens = tokenizer.encode_ordinary_normalized(nfc, NormalizationForm::Nfc);
+        assert_eq!(tokens, tokenizer.encode_ordinary(nfc));
+        assert_eq!(tokenizer.encode_ordinary_normalized(nfd, NormalizationForm::Nfc), tokens);
+        assert_eq!(tokenizer.encode_ordinary_normalized(nfc, NormalizationForm::Nfd),
+                   tokenizer.encode_ordinary(nfd));
+        assert_eq!(tokenizer.encode_ordinary_normalized("\u{ff48}ello", NormalizationForm::Nfkc),
+                   tokenizer.encode_ordinary("hello"));
+    }
+
+    #[test]
+    fn test_pretokenize() {
+        let tokenizer = test_tokenizer();
+        assert_eq!(tokenizer.pretokenize("hello world, it's 2024!"),
+                   vec!["hello", " world", ",", " it", "'s", " 2024", "!"]);
+        assert!(tokenizer.pretokenize("").is_empty());
+
+        let cl100k = test_tokenizer().with_pattern(&model_pattern(Models::CL100KBase)).unwrap();
+        assert_eq!(cl100k.pretokenize("Hello world!"), vec!["Hello", " world", "!"]);
+        assert_eq!(cl100k.pretokenize("DON'T stop 12345"), vec!["DON", "'T", " stop", " ", "123", "45"]);
+        assert_eq!(cl100k.pretokenize("hi\n\n  there"), vec!["hi", "\n\n", " ", " there"]);
+
+        // The gpt2 pattern is case-sensitive on the contractions and keeps the digits together.
+        let gpt2 = test_tokenizer().with_pattern(&model_pattern(Models::GPT2)).unwrap();
+        assert_eq!(gpt2.pretokenize("Hello world!"), vec!["Hello", " world", "!"]);
+        assert_eq!(gpt2.pretokenize("DON'T stop 12345"), vec!["DON", "'", "T", " stop", " 12345"]);
+        assert_eq!(gpt2.pretokenize("don't  stop"), vec!["don", "'t", " ", " stop"]);
+    }
+
+    #[test]
+    fn test_encode_with_bos_eos() {
+        let tokenizer = test_tokenizer();
+        let eot = tokenizer.end_of_text_token();
+        let text = "hello world";
+
+        let tokens = tokenizer.encode_with_bos_eos(text, eot, eot);
+        assert_eq!(tokens, vec![274, 259, 264, 274]);
+        assert_eq!(tokenizer.encode_with_bos_eos(text, None, None), tokenizer.encode_ordinary(text));
+        assert_eq!(tokenizer.encode_with_bos_eos("", eot, eot), vec![274, 274]);
+
+        assert_eq!(tokenizer.decode_without_bos_eos(&tokens, eot, eot, DecodeErrorHandler::Strict).unwrap(), text);
+        assert_eq!(tokenizer.decode_without_bos_eos(&[274, 274], eot, eot, DecodeErrorHandler::Strict).unwrap(), "");
+        // Only the head and the tail are ignored.
+        assert_eq!(tokenizer.decode_without_bos_eos(&[274, 259, 274, 264, 274], eot, eot, DecodeErrorHandler::Strict).unwrap(),
+                   "hello<|endoftext|> world");
+        assert_eq!(tokenizer.decode_without_bos_eos(&tokens, eot, None, DecodeErrorHandler::Strict).unwrap(),
+                   "hello world<|endoftext|>");
+    }
+
+    #[test]
+    fn test_count_batch_parallel() {
+        let tokenizer = test_tokenizer();
+        let texts = (0..2000)
+            .map(|i| format!("hello world {} the {}", "thing ".repeat(i % 7), i))
+            .collect::<Vec<_>>();
+        let texts = texts.iter().map(|text| text.as_str()).collect::<Vec<_>>();
+
+        let expected = texts.iter().map(|text| tokenizer.encode_ordinary(text).len()).collect::<Vec<_>>();
+        assert_eq!(tokenizer.count_batch(&texts), expected);
+        assert_eq!(tokenizer.count_batch_parallel(&texts), expected);
+        assert!(tokenizer.count_batch_parallel(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_encode_with_action() {
+        let tokenizer = test_tokenizer();
+        let text = "hello<|endoftext|> world";
+
+        assert!(tokenizer.encode_with_action(text, Specials::Collection(&[]), Specials::All, DisallowedAction::Error).is_err());
+
+        let tokens = tokenizer
+            .encode_with_action(text, Specials::Collection(&[]), Specials::All, DisallowedAction::EncodeAsText)
+            .unwrap();
+        assert_eq!(tokens, tokenizer.encode_ordinary(text));
+        assert!(!tokens.contains(&274));
+
+        // The allowed specials are still encoded as the special token ids.
+        let tokens = tokenizer
+            .encode_with_action(text, Specials::All, Specials::All, DisallowedAction::EncodeAsText)
+            .unwrap();
+        assert_eq!(tokens, vec![259, 274, 264]);
+    }
+
+    #[test]
+    fn test_decode_with_char_offsets() {
+        let tokenizer = test_tokenizer();
+        let text = "hello \u{e9}\u{1F600} world";
+        let tokens = tokenizer.encode_ordinary(text);
+        assert_eq!(tokens, vec![259, 32, 271, 273, 0x80, 264]);
+
+        let (decoded, offsets) = tokenizer.decode_with_char_offsets(&tokens).unwrap();
+        assert_eq!(decoded, text);
+        // The last byte of the emoji falls within the emoji at the char index 7.
+        assert_eq!(offsets, vec![0, 5, 6, 7, 7, 8]);
+
+        assert!(matches!(tokenizer.decode_with_char_offsets(&[273]), Err(CounterError::ByteDecodeError(_))));
+        assert_eq!(tokenizer.decode_with_char_offsets(&[]).unwrap(), (String::new(), vec![]));
+    }
+
+    #[test]
+    fn test_sanity_check_pattern() {
+        let tokenizer = test_tokenizer();
+        assert!(tokenizer.sanity_check_pattern());
+
+        let mut input = test_input();
+        input.expected_probe_pieces = Some(tokenizer.pretokenize(super::PATTERN_PROBE).len());
+        let tokenizer = OpenAI::try_from(input).unwrap();
+        assert!(tokenizer.sanity_check_pattern());
+
+        // The expectation is kept through the serialization.
+        let restored = OpenAI::from_serialized(&tokenizer.to_serialized().unwrap()).unwrap();
+        assert!(restored.sanity_check_pattern());
+
+        // The pattern splitting the digits like cl100k doesn't match the expectation.
+        let tokenizer = tokenizer.with_pattern(r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+|\s+").unwrap();
+        assert!(!tokenizer.sanity_check_pattern());
+    }
+
+    #[test]
+    fn test_encode_with_pieces() {
+        let tokenizer = test_tokenizer();
+        let text = "hello world, the thing";
+
+        let pieces = tokenizer.encode_with_pieces(text).unwrap();
+        assert_eq!(pieces.iter().map(|(token, _)| *token).collect::<Vec<_>>(), tokenizer.encode_ordinary(text));
+        assert_eq!(pieces.iter().map(|(_, piece)| piece.as_str()).collect::<String>(), text);
+
+        // The emoji is split into two tokens, whose fragments are replaced.
+        let pieces = tokenizer.encode_with_pieces("hello \u{1F600}").unwrap();
+        assert_eq!(pieces, vec![
+            (259, "hello".to_string()),
+            (32, " ".to_string()),
+            (273, "\u{FFFD}".to_string()),
+            (0x80, "\u{FFFD}".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_n_vocab_max_id() {
+        let special_tokens = HashMap::from([("<|max|>".to_string(), u32::MAX)]);
+        let tokenizer = OpenAI::new("test_max".to_string(), TEST_PATTERN.to_string(), test_ranks(), special_tokens, None).unwrap();
+        assert_eq!(tokenizer.n_vocab(), u32::MAX as u64 + 1);
+        assert_eq!(tokenizer.encode("<|max|>", Specials::All, Specials::All).unwrap(), vec![u32::MAX]);
+
+        // The explicit vocabulary size of 0 is rejected without the underflow.
+        let mut input = test_input();
+        input.explicit_n_vocab = Some(0);
+        assert!(OpenAI::try_from(input).is_err());
+    }
 }
@@ -1,12 +1,34 @@
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
 use std::str::{from_utf8, from_utf8_unchecked};
+use std::sync::Arc;
+use fancy_regex::Match;
 use regex::Regex;
 use rustc_hash::FxHashMap as HashMap;
 use crate::errors::{CounterError, CounterResult};
 
 type Rank = u32;
+/// The `[start, end)` byte range of a token in the text.
+type Span = (usize, usize);
+
+/// The piece longer than this is merged with a heap. The vector is faster for the short pieces
+/// thanks to the cache locality, but its rescan is quadratic for the pathological long pieces.
+const HEAP_MERGE_THRESHOLD: usize = 1024;
 
 fn byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
+    if piece.len() > HEAP_MERGE_THRESHOLD {
+        byte_pair_merge_heap(ranks, piece)
+    } else {
+        byte_pair_merge_vec(ranks, piece)
+    }
+}
+
+fn byte_pair_merge_vec(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
+    if piece.is_empty() {
+        // Only the sentinel, so the empty piece has no parts.
+        return vec![(0, Rank::MAX)]
+    }
+
     let mut parts = Vec::with_capacity(piece.len() + 1);
 
     let mut min_rank: (Rank, usize) = (Rank::MAX, usize::MAX);
@@ -52,12 +74,77 @@ fn byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize,
     parts
 }
 
+/// Merges the same way as `byte_pair_merge_vec` in O(m log n). The parts are linked by
+/// their start positions, and the outdated heap entries are skipped when popped.
+fn byte_pair_merge_heap(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
+    if piece.is_empty() {
+        return vec![(0, Rank::MAX)]
+    }
+
+    let len = piece.len();
+    let mut next = (1..=len + 1).collect::<Vec<_>>();
+    let mut prev = (0..=len).map(|i| i.wrapping_sub(1)).collect::<Vec<_>>();
+    let mut part_ranks = vec![Rank::MAX; len + 1];
+
+    // The rank of merging the part starting at `start` with the following part.
+    let get_rank = |next: &Vec<usize>, start: usize| {
+        if next[start] < len {
+            *ranks.get(&piece[start..next[next[start]]]).unwrap_or(&Rank::MAX)
+        } else {
+            Rank::MAX
+        }
+    };
+
+    let mut heap = BinaryHeap::new();
+    for (start, pair) in piece.windows(2).enumerate() {
+        part_ranks[start] = *ranks.get(pair).unwrap_or(&Rank::MAX);
+        if part_ranks[start] != Rank::MAX {
+            heap.push(Reverse((part_ranks[start], start)));
+        }
+    }
+
+    while let Some(Reverse((rank, start))) = heap.pop() {
+        if part_ranks[start] != rank {
+            continue
+        }
+
+        let removed = next[start];
+        next[start] = next[removed];
+        prev[next[removed]] = start;
+        part_ranks[removed] = Rank::MAX;
+
+        part_ranks[start] = get_rank(&next, start);
+        if part_ranks[start] != Rank::MAX {
+            heap.push(Reverse((part_ranks[start], start)));
+        }
+        if start > 0 {
+            let before = prev[start];
+            part_ranks[before] = get_rank(&next, before);
+            if part_ranks[before] != Rank::MAX {
+                heap.push(Reverse((part_ranks[before], before)));
+            }
+        }
+    }
+
+    let mut parts = Vec::new();
+    let mut start = 0;
+    while start <= len {
+        parts.push((start, part_ranks[start]));
+        start = next[start];
+    }
+
+    parts
+}
+
 fn byte_pair_encode(piece: &[u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<Rank> {
-    assert!(piece.len() > 1);
-    byte_pair_merge(&ranks, piece)
-        .windows(2)
-        .map(|part| ranks[&piece[part[0].0..part[1].0]])
-        .collect()
+    match piece.len() {
+        0 => vec![],
+        1 => vec![ranks[piece]],
+        _ => byte_pair_merge(&ranks, piece)
+            .windows(2)
+            .map(|part| ranks[&piece[part[0].0..part[1].0]])
+            .collect(),
+    }
 }
 
 fn byte_pair_split<'a>(piece: &'a [u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<&'a [u8]> {
@@ -68,43 +155,53 @@ fn byte_pair_split<'a>(piece: &'a [u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<&
         .collect()
 }
 
+/// The large tables are shared by `Arc`, so cloning is cheap.
+#[derive(Clone)]
 pub(crate) struct CoreBytePairEncoding {
-    encoder: HashMap<Vec<u8>, Rank>,
+    encoder: Arc<HashMap<Vec<u8>, Rank>>,
     special_tokens_encoder: HashMap<String, Rank>,
-    decoder: HashMap<Rank, Vec<u8>>,
+    decoder: Arc<HashMap<Rank, Vec<u8>>>,
     special_tokens_decoder: HashMap<Rank, Vec<u8>>,
-    regex_tls: Regex,
-    special_regex_tls: Regex,
-    sorted_token_bytes: Vec<Vec<u8>>,
+    regex_tls: fancy_regex::Regex,
+    /// `None` when there is no special token, since the empty pattern would match everywhere.
+    special_regex_tls: Option<Regex>,
+    sorted_token_bytes: Arc<Vec<Vec<u8>>>,
+    max_piece_bytes: Option<usize>,
 }
 
 impl CoreBytePairEncoding {
-    pub(crate) fn new(encoder: HashMap<Vec<u8>, Rank>,
+    pub(crate) fn new(encoder: Arc<HashMap<Vec<u8>, Rank>>,
                       special_tokens_encoder: HashMap<String, Rank>,
                       pattern: &str
     ) -> CounterResult<Self> {
-        let regex_obj = Regex::new(pattern)
-            .map_err(|e| CounterError::RegexError(e.to_string()))?;
-
-        let special_regex = {
-            let escaped_specials = special_tokens_encoder
-                .keys()
+        // The patterns of the OpenAI encodings use the look-ahead like `\s+(?!\S)`, which needs fancy_regex.
+        let regex_obj = fancy_regex::Regex::new(pattern)
+            .map_err(|e| CounterError::RegexError(format!("main pattern: {}", e)))?;
+
+        let special_regex = if special_tokens_encoder.is_empty() {
+            None
+        } else {
+            // The longer specials come first, so a special having another one as its prefix
+            // is matched as a whole.
+            let mut specials = special_tokens_encoder.keys().collect::<Vec<_>>();
+            specials.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+            let escaped_specials = specials
+                .into_iter()
                 .map(|str| regex::escape(str))
                 .collect::<Vec<_>>();
-            Regex::new(&escaped_specials.join("|"))
-                .map_err(|e| CounterError::RegexError(e.to_string()))?
+            let regex = Regex::new(&escaped_specials.join("|"))
+                .map_err(|e| CounterError::RegexError(format!("special-token pattern: {}", e)))?;
+            Some(regex)
         };
 
-        let decoder = encoder
-            .iter()
-            .map(|(key, value)| (*value, key.clone()))
-            .collect::<HashMap<Rank, Vec<u8>>>();
-
-        assert_eq!(
-            encoder.len(), decoder.len(),
-            "Encoder and generated decoder from encoder must have equal length. \
-            Maybe the encoder has duplicate token indices and generate process of decoder overrides some tokens."
-        );
+        let mut decoder = HashMap::<Rank, Vec<u8>>::default();
+        for (key, value) in encoder.iter() {
+            // The duplicated index would make the decoder shorter than the encoder.
+            if let Some(other) = decoder.insert(*value, key.clone()) {
+                return Err(CounterError::ValueError(format!(
+                    "token id {} is duplicated by {:?} and {:?}.", value, other, key)))
+            }
+        }
 
         let special_tokens_decoder = special_tokens_encoder
             .iter()
@@ -118,11 +215,12 @@ impl CoreBytePairEncoding {
         Ok(CoreBytePairEncoding {
             encoder,
             special_tokens_encoder,
-            decoder,
+            decoder: Arc::new(decoder),
             special_tokens_decoder,
             regex_tls: regex_obj,
             special_regex_tls: special_regex,
-            sorted_token_bytes,
+            sorted_token_bytes: Arc::new(sorted_token_bytes),
+            max_piece_bytes: None,
         })
     }
 
@@ -130,53 +228,158 @@ impl CoreBytePairEncoding {
     // Encoding
     // ===================
 
-    pub(crate) fn encode_ordinary(&self, text: &str) -> Vec<Rank> {
+    pub(crate) fn with_max_piece_bytes(mut self, max_piece_bytes: Option<usize>) -> Self {
+        self.max_piece_bytes = max_piece_bytes;
+        self
+    }
+
+    pub(crate) fn max_piece_bytes(&self) -> Option<usize> {
+        self.max_piece_bytes
+    }
+
+    pub(crate) fn pretokenize<'b>(&self, text: &'b str) -> CounterResult<Vec<&'b str>> {
+        self.find_pieces(text).map(|mat| mat.map(|mat| mat.as_str())).collect()
+    }
+
+    pub(crate) fn encode_ordinary(&self, text: &str) -> CounterResult<Vec<Rank>> {
         self.encode_ordinary_native(text)
     }
 
-    pub(crate) fn encode(&self, text: &str, allowed_special: HashSet<&str>) -> Vec<Rank> {
-        let tokens = self.encode_native(text, &allowed_special);
-        tokens.0
+    pub(crate) fn encode_ordinary_into(&self, text: &str, out: &mut Vec<Rank>) -> CounterResult<()> {
+        for mat in self.find_pieces(text) {
+            self.encode_pieces_into(std::iter::once(mat?.as_str()), out);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn encode(&self, text: &str, allowed_special: HashSet<&str>) -> CounterResult<Vec<Rank>> {
+        let tokens = self.encode_native(text, &allowed_special)?;
+        Ok(tokens.0)
     }
 
-    fn encode_bytes(&self, bytes: &[u8]) -> Vec<Rank> {
+    pub(crate) fn encode_bytes(&self, bytes: &[u8]) -> CounterResult<Vec<Rank>> {
         match from_utf8(bytes) {
             Ok(text) => self.encode_ordinary_native(text),
             Err(e) => {
-                let text = unsafe {from_utf8_unchecked(&bytes[..e.valid_up_to()])};
+                let (valid, invalid) = bytes.split_at(e.valid_up_to());
+                let text = unsafe {from_utf8_unchecked(valid)};
                 let (tokens, last_piece_token_len) =
-                    self.encode_native(text, &HashSet::new());
+                    self.encode_native(text, &HashSet::new())?;
                 let (mut tokens, last_piece_token_len) =
                     self.increase_last_piece_token_len(tokens, last_piece_token_len);
 
-                if !tokens.is_empty() && last_piece_token_len > 0 {
-                    // This method can't say correct when niche case that regex split
-                    // the valid UTF-8 and the invalid bytes. So this method should be private.
-                    let mut unstable_bytes = self.decode_native(&tokens[tokens.len() - last_piece_token_len..]);
-                    unstable_bytes.extend_from_slice(&bytes[e.valid_up_to()..]);
+                // The regex can't see the invalid bytes, so the last piece of the valid text
+                // and the whole invalid tail are re-merged as one unit.
+                // The tail is never dropped even if the valid text is empty.
+                let mut unstable_bytes = self.decode_native(&tokens[tokens.len() - last_piece_token_len..]);
+                unstable_bytes.extend_from_slice(invalid);
 
-                    tokens.truncate(tokens.len() - last_piece_token_len);
-                    match self.encoder.get(&unstable_bytes) {
-                        Some(token) => tokens.push(*token),
-                        None => tokens.extend(&byte_pair_encode(&unstable_bytes, &self.encoder)),
+                tokens.truncate(tokens.len() - last_piece_token_len);
+                tokens.extend(self.encode_single_piece(&unstable_bytes));
+                Ok(tokens)
+            }
+        }
+    }
+
+    /// Encodes the text except the last `unstable_pieces` regex pieces, which can change
+    /// when more text follows. Returns the tokens and the byte position where the unstable pieces start.
+    pub(crate) fn encode_ordinary_stable(&self,
+                                         text: &str,
+                                         unstable_pieces: usize
+    ) -> CounterResult<(Vec<Rank>, usize)> {
+        let pieces = self.find_pieces(text).collect::<CounterResult<Vec<_>>>()?;
+        if pieces.len() <= unstable_pieces {
+            return Ok((vec![], 0))
+        }
+
+        let stable_len = pieces.len() - unstable_pieces;
+        let mut ret = vec![];
+        for mat in &pieces[..stable_len] {
+            ret.extend(self.encode_single_piece(mat.as_str().as_bytes()));
+        }
+        let end = pieces.get(stable_len).map_or(text.len(), |mat| mat.start());
+
+        Ok((ret, end))
+    }
+
+    pub(crate) fn encode_ordinary_with_offsets(&self,
+                                               text: &str
+    ) -> CounterResult<(Vec<Rank>, Vec<Span>)> {
+        let mut tokens = vec![];
+        let mut offsets = vec![];
+
+        for mat in self.find_pieces(text) {
+            let mat = mat?;
+            let piece = mat.as_str().as_bytes();
+            let start = mat.start();
+            match self.encoder.get(piece) {
+                Some(token) => {
+                    tokens.push(*token);
+                    offsets.push((start, mat.end()));
+                }
+                None => {
+                    // Each sub-token of the piece has its own sub-span.
+                    let mut chunk_start = start;
+                    for chunk in self.piece_chunks(piece) {
+                        for part in byte_pair_merge(&self.encoder, chunk).windows(2) {
+                            tokens.push(self.encoder[&chunk[part[0].0..part[1].0]]);
+                            offsets.push((chunk_start + part[0].0, chunk_start + part[1].0));
+                        }
+                        chunk_start += chunk.len();
                     }
                 }
-                tokens
+            }
+        }
+        Ok((tokens, offsets))
+    }
+
+    /// Encodes the text marking the tokens produced by the byte pair merges,
+    /// i.e. those of the pieces which are not a token as a whole.
+    pub(crate) fn encode_ordinary_annotated(&self, text: &str) -> CounterResult<Vec<(Rank, bool)>> {
+        let mut ret = vec![];
 
+        for mat in self.find_pieces(text) {
+            let piece = mat?.as_str().as_bytes();
+            match self.encoder.get(piece) {
+                Some(token) => ret.push((*token, false)),
+                None => ret.extend(self.byte_pair_encode_piece(piece).into_iter().map(|token| (token, true))),
             }
         }
+        Ok(ret)
+    }
+
+    /// Encodes the text until the number of the tokens reaches `max_tokens`.
+    pub(crate) fn encode_ordinary_head(&self, text: &str, max_tokens: usize) -> CounterResult<Vec<Rank>> {
+        let mut ret = vec![];
+
+        for mat in self.find_pieces(text) {
+            if ret.len() >= max_tokens {
+                break
+            }
+            ret.extend(self.encode_single_piece(mat?.as_str().as_bytes()));
+        }
+        ret.truncate(max_tokens);
+        Ok(ret)
+    }
+
+    pub(crate) fn count_ordinary(&self, text: &str) -> CounterResult<usize> {
+        self.count_ordinary_native(text)
+    }
+
+    pub(crate) fn count(&self, text: &str, allowed_special: HashSet<&str>) -> CounterResult<usize> {
+        self.count_native(text, &allowed_special)
     }
 
     pub(crate) fn encode_with_unstable(&self,
                                        text: &str,
                                        allowed_special: HashSet<&str>
-    ) -> (Vec<Rank>, Vec<Vec<Rank>>) {
+    ) -> CounterResult<(Vec<Rank>, Vec<Vec<Rank>>)> {
         let (tokens, completions) =
-            self.encode_unstable_native(text, &allowed_special);
+            self.encode_unstable_native(text, &allowed_special)?;
 
         let completions = completions.iter().cloned().collect::<Vec<Vec<_>>>();
 
-        (tokens, completions)
+        Ok((tokens, completions))
     }
 
     pub(crate) fn encode_single_token(&self, piece: &[u8]) -> CounterResult<u32> {
@@ -193,11 +396,14 @@ impl CoreBytePairEncoding {
     }
 
     pub(crate) fn encode_single_piece(&self, piece: &[u8]) -> Vec<Rank> {
+        if piece.is_empty() {
+            return vec![]
+        }
         if let Some(token) = self.encoder.get(piece) {
             vec![*token]
         }
         else {
-            byte_pair_encode(piece, &self.encoder)
+            self.byte_pair_encode_piece(piece)
         }
     }
 
@@ -209,12 +415,37 @@ impl CoreBytePairEncoding {
         self.decode_native(tokens)
     }
 
+    pub(crate) fn decode_bytes_into(&self, tokens: &[Rank], out: &mut Vec<u8>) {
+        out.reserve(tokens.len() * 2);
+        for token in tokens {
+            let token_bytes = self.decoder
+                .get(token)
+                .unwrap_or_else(|| &self.special_tokens_decoder[token]);
+            out.extend(token_bytes);
+        }
+    }
+
+    pub(crate) fn decode_bytes_checked(&self, tokens: &[Rank]) -> CounterResult<Vec<u8>> {
+        let unknown = tokens
+            .iter()
+            .find(|token| !self.decoder.contains_key(token) && !self.special_tokens_decoder.contains_key(token));
+        if let Some(token) = unknown {
+            return Err(CounterError::KeyError(token.to_string()))
+        }
+
+        Ok(self.decode_native(tokens))
+    }
+
     pub(crate) fn decode_single_token_bytes(&self, token: Rank) -> CounterResult<Vec<u8>> {
+        self.decode_single_token_bytes_ref(token).map(|bytes| bytes.to_owned())
+    }
+
+    pub(crate) fn decode_single_token_bytes_ref(&self, token: Rank) -> CounterResult<&[u8]> {
         if let Some(bytes) = self.decoder.get(&token) {
-            return Ok(bytes.to_owned())
+            return Ok(bytes)
         }
         if let Some(bytes) = self.special_tokens_decoder.get(&token) {
-            return Ok(bytes.to_owned())
+            return Ok(bytes)
         }
 
         Err(CounterError::KeyError(token.to_string()))
@@ -224,6 +455,60 @@ impl CoreBytePairEncoding {
     // Miscellaneous
     // ===================
 
+    pub(crate) fn iter_vocab(&self, include_special: bool) -> impl Iterator<Item = (Rank, &[u8])> + '_ {
+        let specials = self.special_tokens_decoder
+            .iter()
+            .filter(move |_| include_special);
+
+        self.decoder
+            .iter()
+            .chain(specials)
+            .map(|(token, bytes)| (*token, bytes.as_slice()))
+    }
+
+    pub(crate) fn special_regex(&self) -> Option<&Regex> {
+        self.special_regex_tls.as_ref()
+    }
+
+    pub(crate) fn find_special_tokens<'b>(&self, text: &'b str) -> Vec<(&'b str, usize)> {
+        match &self.special_regex_tls {
+            Some(regex) => regex.find_iter(text).map(|mat| (mat.as_str(), mat.start())).collect(),
+            None => vec![],
+        }
+    }
+
+    pub(crate) fn longest_token_prefix(&self, bytes: &[u8]) -> Option<(Rank, usize)> {
+        // The tokens starting with the same prefix are contiguous in the sorted vector,
+        // and the range narrows down as the prefix grows.
+        let mut lo = 0;
+        let mut hi = self.sorted_token_bytes.len();
+        let mut longest = None;
+
+        for len in 1..=bytes.len() {
+            let prefix = &bytes[..len];
+            let range = &self.sorted_token_bytes[lo..hi];
+            let start = lo + range.partition_point(|token| token.as_slice() < prefix);
+            let end = lo + range.partition_point(|token| token.as_slice() < prefix || token.starts_with(prefix));
+            if start == end {
+                break
+            }
+            if self.sorted_token_bytes[start] == prefix {
+                longest = Some((self.encoder[prefix], len));
+            }
+            (lo, hi) = (start, end);
+        }
+
+        longest
+    }
+
+    pub(crate) fn vocab_len(&self) -> usize {
+        self.decoder.len()
+    }
+
+    pub(crate) fn sorted_token_bytes(&self) -> &[Vec<u8>] {
+        &self.sorted_token_bytes
+    }
+
     pub(crate) fn token_byte_values(&self) -> Vec<Vec<u8>> {
         self.sorted_token_bytes
             .iter()
@@ -236,33 +521,65 @@ impl CoreBytePairEncoding {
     // ===================
 
     fn decode_native(&self, tokens: &[Rank]) -> Vec<u8> {
-        let mut ret = Vec::with_capacity(tokens.len() * 2);
-        for token in tokens {
-            let token_bytes = self.decoder
-                .get(token)
-                .unwrap_or_else(|| &self.special_tokens_decoder[token]);
-            ret.extend(token_bytes);
-        }
+        let mut ret = Vec::new();
+        self.decode_bytes_into(tokens, &mut ret);
         ret
     }
 
-    fn encode_ordinary_native(&self, text: &str) -> Vec<Rank> {
-        let regex = &self.regex_tls;
+    /// Splits the text into the pieces by the pre-tokenization regex.
+    /// The match fails only when the backtracking exceeds the limit of fancy_regex,
+    /// and the caller must stop at the first error since the following matches fail as well.
+    fn find_pieces<'r, 't: 'r>(&'r self, text: &'t str) -> impl Iterator<Item = CounterResult<Match<'t>>> + 'r {
+        self.regex_tls
+            .find_iter(text)
+            .map(|mat| mat.map_err(|e| CounterError::RegexError(format!("main pattern failed to match: {}", e))))
+    }
+
+    fn encode_ordinary_native(&self, text: &str) -> CounterResult<Vec<Rank>> {
         let mut ret = vec![];
+        self.encode_ordinary_into(text, &mut ret)?;
+        Ok(ret)
+    }
 
+    pub(crate) fn encode_ordinary_with_regex(&self, text: &str, regex: &fancy_regex::Regex) -> CounterResult<Vec<Rank>> {
+        let mut ret = vec![];
         for mat in regex.find_iter(text) {
-            let piece = mat.as_str().as_bytes();
+            let mat = mat.map_err(|e| CounterError::RegexError(format!("pattern failed to match: {}", e)))?;
+            self.encode_pieces_into(std::iter::once(mat.as_str()), &mut ret);
+        }
+        Ok(ret)
+    }
+
+    /// Splits the piece longer than `max_piece_bytes` into the chunks of that size,
+    /// which are merged separately. Every path merging a piece must go through this,
+    /// so the encoding, the counting and the offsets agree.
+    fn piece_chunks<'p>(&self, piece: &'p [u8]) -> std::slice::Chunks<'p, u8> {
+        let chunk_size = match self.max_piece_bytes {
+            Some(max_piece_bytes) if piece.len() > max_piece_bytes => max_piece_bytes,
+            _ => piece.len(),
+        };
+        piece.chunks(chunk_size.max(1))
+    }
+
+    /// Encodes the piece which is not a token as a whole by the byte pair merges.
+    fn byte_pair_encode_piece(&self, piece: &[u8]) -> Vec<Rank> {
+        self.piece_chunks(piece)
+            .flat_map(|chunk| byte_pair_encode(chunk, &self.encoder))
+            .collect()
+    }
+
+    fn encode_pieces_into<'t>(&self, pieces: impl Iterator<Item = &'t str>, out: &mut Vec<Rank>) {
+        for piece in pieces {
+            let piece = piece.as_bytes();
             match self.encoder.get(piece) {
-                Some(token) => ret.push(*token),
-                None => ret.extend(&byte_pair_encode(piece, &self.encoder)),
+                Some(token) => out.push(*token),
+                None => out.extend(&self.byte_pair_encode_piece(piece)),
             }
         }
-        ret
     }
 
-    fn encode_native(&self, text: &str, allowed_special: &HashSet<&str>) -> (Vec<Rank>, usize) {
-        let special_regex = &self.special_regex_tls;
-        let regex = &self.regex_tls;
+    fn encode_native(&self, text: &str, allowed_special: &HashSet<&str>) -> CounterResult<(Vec<Rank>, usize)> {
+        let special_regex = self.special_regex_tls.as_ref();
         let mut ret = vec![];
 
         let mut start = 0;
@@ -273,7 +590,7 @@ impl CoreBytePairEncoding {
             let mut start_find = start;
 
             loop {
-                next_special = special_regex.find_at(text, start_find);
+                next_special = special_regex.and_then(|regex| regex.find_at(text, start_find));
                 match next_special {
                     Some(special_pos) => {
                         if allowed_special
@@ -287,14 +604,14 @@ impl CoreBytePairEncoding {
             }
             let end = next_special.map_or(text.len(), |special_pos| special_pos.start());
 
-            for mat in regex.find_iter(&text[start..end]) {
-                let piece = mat.as_str().as_bytes();
+            for mat in self.find_pieces(&text[start..end]) {
+                let piece = mat?.as_str().as_bytes();
                 if let Some(token) = self.encoder.get(piece) {
                     last_piece_token_len = 1;
                     ret.push(*token);
                     continue;
                 }
-                let tokens = byte_pair_encode(piece, &self.encoder);
+                let tokens = self.byte_pair_encode_piece(piece);
                 last_piece_token_len = tokens.len();
                 ret.extend(&tokens);
             }
@@ -310,7 +627,63 @@ impl CoreBytePairEncoding {
                 None => break,
             }
         }
-        (ret, last_piece_token_len)
+        Ok((ret, last_piece_token_len))
+    }
+
+    fn count_piece(&self, piece: &[u8]) -> usize {
+        if self.encoder.contains_key(piece) {
+            1
+        }
+        else {
+            // The merged parts include the sentinel at the end of each chunk.
+            self.piece_chunks(piece)
+                .map(|chunk| byte_pair_merge(&self.encoder, chunk).len() - 1)
+                .sum()
+        }
+    }
+
+    fn count_ordinary_native(&self, text: &str) -> CounterResult<usize> {
+        self.find_pieces(text)
+            .map(|mat| mat.map(|mat| self.count_piece(mat.as_str().as_bytes())))
+            .sum()
+    }
+
+    fn count_native(&self, text: &str, allowed_special: &HashSet<&str>) -> CounterResult<usize> {
+        let special_regex = self.special_regex_tls.as_ref();
+        let mut count = 0;
+
+        let mut start = 0;
+
+        loop {
+            let mut next_special;
+            let mut start_find = start;
+
+            loop {
+                next_special = special_regex.and_then(|regex| regex.find_at(text, start_find));
+                match next_special {
+                    Some(special_pos) => {
+                        if allowed_special
+                            .contains(&text[special_pos.start()..special_pos.end()]) {
+                            break
+                        }
+                        start_find = special_pos.start() + 1
+                    }
+                    None => break
+                }
+            }
+            let end = next_special.map_or(text.len(), |special_pos| special_pos.start());
+
+            count += self.count_ordinary_native(&text[start..end])?;
+
+            match next_special {
+                Some(special_pos) => {
+                    count += 1;
+                    start = special_pos.end();
+                }
+                None => break,
+            }
+        }
+        Ok(count)
     }
 
     fn increase_last_piece_token_len(&self,
@@ -342,11 +715,11 @@ impl CoreBytePairEncoding {
     fn encode_unstable_native(&self,
                               text: &str,
                               allowed_special: &HashSet<&str>
-    ) -> (Vec<Rank>, HashSet<Vec<Rank>>) {
-        let (tokens, last_piece_token_len) = self.encode_native(text, allowed_special);
+    ) -> CounterResult<(Vec<Rank>, HashSet<Vec<Rank>>)> {
+        let (tokens, last_piece_token_len) = self.encode_native(text, allowed_special)?;
         if last_piece_token_len == 0 {
             // If last_piece_token_len is zero, the last token was a special token and we have no unstable bytes
-            return (tokens, HashSet::new())
+            return Ok((tokens, HashSet::new()))
         }
         let (mut tokens, last_piece_token_len) =
             self.increase_last_piece_token_len(tokens, last_piece_token_len);
@@ -356,7 +729,7 @@ impl CoreBytePairEncoding {
 
         let mut completions = HashSet::new();
         if unstable_bytes.is_empty() {
-            return (tokens, completions)
+            return Ok((tokens, completions))
         }
 
         let mut point = self.sorted_token_bytes
@@ -379,7 +752,7 @@ impl CoreBytePairEncoding {
             while point < self.sorted_token_bytes.len() && self.sorted_token_bytes[point].starts_with(suffix) {
                 let possibility = [prefix, self.sorted_token_bytes[point].as_slice()].concat();
                 let encoded = match from_utf8(&possibility) {
-                    Ok(str) => self.encode_ordinary_native(str),
+                    Ok(str) => self.encode_ordinary_native(str)?,
                     Err(_) => byte_pair_encode(&possibility, &self.encoder),
                 };
                 let mut seq = Vec::new();
@@ -412,6 +785,96 @@ impl CoreBytePairEncoding {
             }
         }
 
-        (tokens, completions)
+        Ok((tokens, completions))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use rustc_hash::FxHashMap as HashMap;
+    use crate::errors::CounterError;
+    use crate::tokenizer::openai::bpe::CoreBytePairEncoding;
+    use crate::tokenizer::openai::testing::{test_ranks, test_tokenizer, TEST_PATTERN};
+
+    #[test]
+    fn test_encode_empty_and_single_byte() {
+        let bpe = &test_tokenizer().bpe_base;
+
+        assert_eq!(bpe.encode_ordinary("").unwrap(), Vec::<u32>::new());
+        assert_eq!(bpe.encode_single_piece(b""), Vec::<u32>::new());
+        assert_eq!(bpe.encode_bytes(b"").unwrap(), Vec::<u32>::new());
+        assert_eq!(bpe.count_ordinary("").unwrap(), 0);
+
+        assert_eq!(bpe.encode_ordinary(" ").unwrap(), vec![b' ' as u32]);
+        assert_eq!(bpe.count_ordinary(" ").unwrap(), 1);
+
+        // The first byte of an emoji is a single byte token.
+        assert_eq!(bpe.encode_single_piece(b"\xf0"), vec![0xf0]);
+        assert_eq!(bpe.encode_bytes(b"\xf0").unwrap(), vec![0xf0]);
+        assert_eq!(bpe.encode_bytes(b" \xf0").unwrap(), vec![b' ' as u32, 0xf0]);
+    }
+
+    #[test]
+    fn test_byte_pair_encode_short_pieces() {
+        let bpe = &test_tokenizer().bpe_base;
+
+        assert_eq!(super::byte_pair_encode(b"", &bpe.encoder), Vec::<u32>::new());
+        assert_eq!(super::byte_pair_encode(b"a", &bpe.encoder), vec![b'a' as u32]);
+        assert_eq!(super::byte_pair_merge(&bpe.encoder, b"").len() - 1, 0);
+    }
+
+    #[test]
+    fn test_byte_pair_merge_heap() {
+        let bpe = &test_tokenizer().bpe_base;
+        let alphabet = "hello world the thing \u{1F600}é".as_bytes();
+
+        // xorshift keeps the generated inputs reproducible.
+        let mut state = 0x2545f491u32;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as usize
+        };
+
+        for _ in 0..300 {
+            let piece = (0..next() % 64).map(|_| alphabet[next() % alphabet.len()]).collect::<Vec<_>>();
+            assert_eq!(super::byte_pair_merge_heap(&bpe.encoder, &piece),
+                       super::byte_pair_merge_vec(&bpe.encoder, &piece));
+        }
+
+        for piece in [b"l".repeat(5000), b"hello".repeat(1000), b"ll".repeat(2001)] {
+            assert!(piece.len() > super::HEAP_MERGE_THRESHOLD);
+            assert_eq!(super::byte_pair_merge(&bpe.encoder, &piece),
+                       super::byte_pair_merge_vec(&bpe.encoder, &piece));
+        }
+    }
+
+    #[test]
+    fn test_invalid_main_pattern() {
+        let bpe = &test_tokenizer().bpe_base;
+
+        let result = super::CoreBytePairEncoding::new(bpe.encoder.clone(), bpe.special_tokens_encoder.clone(), r"(\p{L}+");
+        match result {
+            Err(CounterError::RegexError(message)) => assert!(message.starts_with("main pattern: "), "{}", message),
+            _ => panic!("the invalid main pattern must be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_without_special_tokens() {
+        let bpe = CoreBytePairEncoding::new(Arc::new(test_ranks().into_iter().collect()),
+                                            HashMap::default(),
+                                            TEST_PATTERN).unwrap();
+        assert!(bpe.special_regex().is_none());
+
+        let text = "hello world<|endoftext|>";
+        let expected = bpe.encode_ordinary(text).unwrap();
+        assert_eq!(bpe.encode(text, HashSet::new()).unwrap(), expected);
+        assert_eq!(bpe.count(text, HashSet::new()).unwrap(), expected.len());
+        assert_eq!(bpe.encode("", HashSet::new()).unwrap(), Vec::<u32>::new());
+        assert!(bpe.find_special_tokens(text).is_empty());
     }
 }
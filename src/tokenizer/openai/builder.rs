@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use crate::tokenizer::openai::{check_special_ids, OpenAI, OpenAIInput};
+use crate::errors::{CounterError, CounterResult};
+
+/// Builder of the `OpenAI` tokenizer.
+///
+/// It helps to start from the merge ranks of a base model and append project-specific
+/// special tokens (e.g. `<|tool_call|>`) without rebuilding the whole map by hand.
+#[derive(Clone, Default)]
+pub struct OpenAIBuilder {
+    name: Option<String>,
+    pattern: Option<String>,
+    merge_able_ranks: HashMap<Vec<u8>, u32>,
+    special_tokens: HashMap<String, u32>,
+    explicit_n_vocab: Option<u32>,
+    max_piece_bytes: Option<usize>,
+    expected_probe_pieces: Option<usize>,
+    require_full_byte_coverage: bool,
+}
+
+impl OpenAIBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the name of the tokenizer.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Sets the pattern string used for the pre-tokenization.
+    pub fn pattern(mut self, pattern: &str) -> Self {
+        self.pattern = Some(pattern.to_string());
+        self
+    }
+
+    /// Sets the merge ranks mapping byte sequences to the token ids.
+    pub fn mergeable_ranks(mut self, merge_able_ranks: HashMap<Vec<u8>, u32>) -> Self {
+        self.merge_able_ranks = merge_able_ranks;
+        self
+    }
+
+    /// Adds a special token. If the token is already registered, the id is overwritten.
+    pub fn add_special_token(mut self, token: &str, id: u32) -> Self {
+        self.special_tokens.insert(token.to_string(), id);
+        self
+    }
+
+    /// Sets the explicit number of vocabulary tokens.
+    pub fn explicit_n_vocab(mut self, explicit_n_vocab: u32) -> Self {
+        self.explicit_n_vocab = Some(explicit_n_vocab);
+        self
+    }
+
+    /// Sets the maximum byte length of a piece encoded by the byte pair merges.
+    /// See `OpenAI::with_max_piece_bytes` for the tradeoff.
+    pub fn max_piece_bytes(mut self, max_piece_bytes: usize) -> Self {
+        self.max_piece_bytes = Some(max_piece_bytes);
+        self
+    }
+
+    /// Sets whether the merge ranks must have a token for every single byte.
+    /// See `OpenAI::with_byte_coverage_check`.
+    pub fn require_full_byte_coverage(mut self, require_full_byte_coverage: bool) -> Self {
+        self.require_full_byte_coverage = require_full_byte_coverage;
+        self
+    }
+
+    /// Builds the `OpenAI` tokenizer.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `CounterResult` that resolves to an `OpenAI` tokenizer on success.
+    /// `CounterError::ValueError` is returned when the name or the pattern is not set,
+    /// a special token id collides with a merge rank or another special token,
+    /// or a single byte is missing while `require_full_byte_coverage` is set.
+    pub fn build(self) -> CounterResult<OpenAI> {
+        let name = self.name.ok_or_else(|| CounterError::ValueError(
+            "name of the tokenizer is not set.".to_string()))?;
+        let pattern = self.pattern.ok_or_else(|| CounterError::ValueError(
+            "pattern of the tokenizer is not set.".to_string()))?;
+
+        check_special_ids(&self.merge_able_ranks, &self.special_tokens)?;
+
+        let tokenizer = OpenAI::new(
+            name,
+            pattern,
+            self.merge_able_ranks,
+            self.special_tokens,
+            self.explicit_n_vocab,
+        )?
+            .with_max_piece_bytes(self.max_piece_bytes)
+            .with_expected_probe_pieces(self.expected_probe_pieces);
+
+        if self.require_full_byte_coverage {
+            return tokenizer.with_byte_coverage_check()
+        }
+
+        Ok(tokenizer)
+    }
+}
+
+impl From<OpenAIInput> for OpenAIBuilder {
+    fn from(value: OpenAIInput) -> Self {
+        Self {
+            name: Some(value.name),
+            pattern: Some(value.pattern),
+            merge_able_ranks: value.merge_able_ranks,
+            special_tokens: value.special_tokens,
+            explicit_n_vocab: value.explicit_n_vocab,
+            max_piece_bytes: None,
+            expected_probe_pieces: value.expected_probe_pieces,
+            require_full_byte_coverage: value.require_full_byte_coverage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tokenizer::openai::builder::OpenAIBuilder;
+    use crate::tokenizer::openai::Specials;
+    use crate::tokenizer::openai::testing::{test_input, test_ranks, TEST_END_OF_TEXT, TEST_PATTERN};
+
+    #[test]
+    fn test_builder_extra_special_token() {
+        let mut input = test_input();
+        input.explicit_n_vocab = None;
+
+        let tokenizer = OpenAIBuilder::from(input)
+            .name("test_base_tool")
+            .add_special_token("<|tool_call|>", TEST_END_OF_TEXT + 1)
+            .build()
+            .unwrap();
+
+        let tokens = tokenizer
+            .encode("hello<|tool_call|>", Specials::All, Specials::All)
+            .unwrap();
+        assert_eq!(tokens.last(), Some(&(TEST_END_OF_TEXT + 1)));
+        assert_eq!(tokenizer.n_vocab(), TEST_END_OF_TEXT as u64 + 2);
+    }
+
+    #[test]
+    fn test_builder_collision() {
+        let builder = OpenAIBuilder::new()
+            .name("test_base")
+            .pattern(TEST_PATTERN)
+            .mergeable_ranks(test_ranks());
+
+        assert!(builder.clone().add_special_token("<|tool_call|>", 256).build().is_err());
+        assert!(builder
+            .add_special_token("<|a|>", TEST_END_OF_TEXT)
+            .add_special_token("<|b|>", TEST_END_OF_TEXT)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_builder_missing_pattern() {
+        assert!(OpenAIBuilder::new().name("test_base").mergeable_ranks(test_ranks()).build().is_err());
+    }
+}
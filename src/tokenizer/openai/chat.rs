@@ -0,0 +1,137 @@
+use crate::tokenizer::openai::{OpenAI, Specials};
+use crate::errors::{CounterError, CounterResult};
+
+/// A message of the chat completion request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatMessage {
+    pub role: String,
+    pub name: Option<String>,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            name: None,
+            content: content.to_string(),
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+}
+
+/// The overhead tokens of the chat format as (tokens per message, tokens per name, tokens priming reply).
+/// Every message is wrapped like `<|start|>{role/name}\n{content}<|end|>\n`
+/// and every reply is primed with `<|start|>assistant<|message|>`.
+fn chat_overhead(encoding_name: &str) -> CounterResult<(usize, usize, usize)> {
+    match encoding_name {
+        "cl100k_base" | "cl100k_im" | "o200k_base" => Ok((3, 1, 3)),
+        _ => Err(CounterError::ValueError(format!(
+            "counting chat tokens is not supported for the '{}' encoding.", encoding_name))),
+    }
+}
+
+impl OpenAI {
+    /// Counts the tokens of the chat completion request including the per-message
+    /// and per-reply overhead of the chat format.
+    ///
+    /// # Arguments
+    ///
+    /// * `messages` - The messages of the chat completion request.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the number of the prompt tokens.
+    /// Returns `CounterError::ValueError` if the encoding is not for chat models
+    /// or a message contains a special token.
+    pub fn count_chat_tokens(&self, messages: &[ChatMessage]) -> CounterResult<usize> {
+        count_messages(messages, chat_overhead(&self.name)?, |text| {
+            self.count(text, Specials::Collection(&[]), Specials::All)
+        })
+    }
+}
+
+fn count_messages<F>(messages: &[ChatMessage],
+                     overhead: (usize, usize, usize),
+                     count: F
+) -> CounterResult<usize>
+where
+    F: Fn(&str) -> CounterResult<usize>
+{
+    let (tokens_per_message, tokens_per_name, tokens_per_reply) = overhead;
+
+    let mut num_tokens = 0;
+    for message in messages {
+        num_tokens += tokens_per_message;
+        num_tokens += count(&message.role)?;
+        num_tokens += count(&message.content)?;
+        if let Some(name) = &message.name {
+            num_tokens += tokens_per_name;
+            num_tokens += count(name)?;
+        }
+    }
+    num_tokens += tokens_per_reply;
+
+    Ok(num_tokens)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tokenizer::openai::builder::OpenAIBuilder;
+    use std::collections::HashMap;
+    use crate::tokenizer::openai::chat::{chat_overhead, count_messages, ChatMessage};
+    use crate::tokenizer::openai::testing::{test_input, test_tokenizer};
+
+    #[test]
+    fn test_count_cookbook_example() {
+        // The example of "How to count tokens with tiktoken" in the OpenAI cookbook,
+        // which is documented as 129 prompt tokens for gpt-4 and gpt-3.5-turbo-0613.
+        let messages = [
+            ChatMessage::new("system", "You are a helpful, pattern-following assistant that translates corporate jargon into plain English."),
+            ChatMessage::new("system", "New synergies will help drive top-line growth.").with_name("example_user"),
+            ChatMessage::new("system", "Things working well together will increase revenue.").with_name("example_assistant"),
+            ChatMessage::new("system", "Let's circle back when we have more bandwidth to touch base on opportunities for increased leverage.").with_name("example_user"),
+            ChatMessage::new("system", "Let's talk later when we're less busy about how to do better.").with_name("example_assistant"),
+            ChatMessage::new("user", "This late pivot means we don't have time to boil the ocean for the client deliverable."),
+        ];
+        // The number of the cl100k_base tokens of every role, name and content, 104 tokens in total.
+        let token_counts = messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .zip([19, 11, 8, 18, 15, 19])
+            .chain([("system", 1), ("user", 1), ("example_user", 2), ("example_assistant", 2)])
+            .collect::<HashMap<_, usize>>();
+
+        let count = |text: &str| Ok(token_counts[text]);
+        assert_eq!(count_messages(&messages, chat_overhead("cl100k_base").unwrap(), count).unwrap(), 129);
+        assert_eq!(count_messages(&[], chat_overhead("cl100k_base").unwrap(), count).unwrap(), 3);
+        assert!(chat_overhead("p50k_base").is_err());
+    }
+
+    #[test]
+    fn test_count_chat_tokens() {
+        let tokenizer = OpenAIBuilder::from(test_input()).name("cl100k_base").build().unwrap();
+        let messages = [
+            ChatMessage::new("system", "hello the world"),
+            ChatMessage::new("user", "the thing").with_name("example_user"),
+        ];
+
+        // "system" is 6 bytes and "hello the world" is 3 merged tokens,
+        // "user" is 4 bytes, "the thing" is "the", " th", "ing" and "example_user" is 12 bytes.
+        assert_eq!(tokenizer.count_chat_tokens(&messages).unwrap(), (3 + 6 + 3) + (3 + 4 + 4) + (1 + 12) + 3);
+        assert_eq!(tokenizer.count_chat_tokens(&[]).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_count_chat_tokens_errors() {
+        let tokenizer = OpenAIBuilder::from(test_input()).name("cl100k_base").build().unwrap();
+        assert!(tokenizer.count_chat_tokens(&[ChatMessage::new("user", "<|endoftext|>")]).is_err());
+
+        // The test tokenizer isn't for chat models.
+        assert!(test_tokenizer().count_chat_tokens(&[ChatMessage::new("user", "hello")]).is_err());
+    }
+}
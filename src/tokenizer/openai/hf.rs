@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fs::{read, File};
+use std::io::Write;
+use std::path::Path;
+use serde_json::{json, Map, Value};
+use crate::tokenizer::openai::OpenAI;
+use crate::tokenizer::openai::load::{bytes_to_unicode, unicode_to_bytes};
+use crate::errors::{CounterError, CounterResult};
+
+impl OpenAI {
+    /// Exports the vocabulary as a minimal Hugging Face `tokenizer.json`
+    /// having a `ByteLevel` pre-tokenizer and a `BPE` model section.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the output JSON file.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` which is `Err` when the file can't be written
+    /// or a merged token can't be split into two tokens.
+    pub fn export_hf_tokenizer_json(&self, path: &str) -> CounterResult<()> {
+        let json = self.to_hf_tokenizer_json()?;
+        let contents =
+            serde_json::to_vec_pretty(&json).map_err(|e| CounterError::ValueError(e.to_string()))?;
+
+        let mut file = File::create(Path::new(path)).map_err(|e| CounterError::IOError(e.to_string()))?;
+        file.write_all(&contents).map_err(|e| CounterError::IOError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Constructs a tokenizer from a Hugging Face `tokenizer.json` of a byte-level BPE model.
+    ///
+    /// The vocabulary is converted back to raw bytes by the GPT-2 byte-to-unicode table,
+    /// and the token ids are used as the merge ranks. The pre-tokenization pattern is taken from
+    /// the `Split` pre-tokenizer, or the GPT-2 pattern is used for the `ByteLevel` one with `use_regex`.
+    /// The added tokens become the special tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the `tokenizer.json`. Its file stem becomes the name of the tokenizer.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the `OpenAI` tokenizer. Returns `CounterError::IOError`
+    /// if the file can't be read and `CounterError::ValueError` if the model is not a byte-level BPE,
+    /// the merges are not ordered by the token ids or a single byte doesn't have its own token.
+    pub fn from_hf_tokenizer_json(path: &str) -> CounterResult<OpenAI> {
+        let contents = read(Path::new(path)).map_err(|e| CounterError::IOError(e.to_string()))?;
+        let json =
+            serde_json::from_slice::<Value>(&contents).map_err(|e| CounterError::ValueError(e.to_string()))?;
+        let name = Path::new(path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        Self::from_hf_tokenizer_value(name, &json)
+    }
+
+    fn from_hf_tokenizer_value(name: String, json: &Value) -> CounterResult<OpenAI> {
+        let model = &json["model"];
+        if model["type"] != "BPE" {
+            return Err(CounterError::ValueError(format!(
+                "unsupported model type {} of tokenizer.json: only the byte-level BPE is supported.", model["type"])))
+        }
+
+        let byte_decoder = unicode_to_bytes();
+        let to_bytes = |token: &str| {
+            token
+                .chars()
+                .map(|char| byte_decoder.get(&char).cloned())
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| CounterError::ValueError(format!(
+                    "token {:?} is not byte-level: it has a character out of the byte-to-unicode table.", token)))
+        };
+        let to_id = |value: &Value| {
+            value
+                .as_u64()
+                .and_then(|id| u32::try_from(id).ok())
+                .ok_or_else(|| CounterError::ValueError(format!("token id {} is not a valid u32.", value)))
+        };
+
+        let mut special_tokens = HashMap::new();
+        for added_token in json["added_tokens"].as_array().map(|tokens| tokens.as_slice()).unwrap_or_default() {
+            let content = added_token["content"]
+                .as_str()
+                .ok_or_else(|| CounterError::ValueError(format!("invalid added token {}.", added_token)))?;
+            special_tokens.insert(content.to_string(), to_id(&added_token["id"])?);
+        }
+
+        // The added tokens are also in the vocabulary of e.g. GPT-2 and RoBERTa,
+        // so they are removed from the merge ranks like `data_gym_to_mergeable_bpe_ranks` does.
+        let vocab = model["vocab"]
+            .as_object()
+            .ok_or_else(|| CounterError::ValueError("model.vocab of tokenizer.json is not an object.".to_string()))?;
+        let mut merge_able_ranks = HashMap::with_capacity(vocab.len());
+        for (token, id) in vocab.iter().filter(|(token, _)| !special_tokens.contains_key(token.as_str())) {
+            merge_able_ranks.insert(to_bytes(token)?, to_id(id)?);
+        }
+
+        // This crate merges the pair making the lowest token id first,
+        // so the merges must be in the order of the ids of the merged tokens.
+        let merges = model["merges"].as_array().map(|merges| merges.as_slice()).unwrap_or_default();
+        let mut last_id = None;
+        for merge in merges {
+            let pair = match merge {
+                Value::String(pair) => pair.split_once(' '),
+                Value::Array(pair) if pair.len() == 2 => pair[0].as_str().zip(pair[1].as_str()),
+                _ => None,
+            };
+            let (first, second) = pair.ok_or_else(|| CounterError::ValueError(format!("invalid merge {}.", merge)))?;
+            let merged = [to_bytes(first)?, to_bytes(second)?].concat();
+            let id = merge_able_ranks.get(&merged).cloned().ok_or_else(|| CounterError::ValueError(format!(
+                "the merged token of {} is not in the vocabulary.", merge)))?;
+            if last_id.is_some_and(|last_id| last_id >= id) {
+                return Err(CounterError::ValueError(format!(
+                    "merge {} is not in the order of the token ids.", merge)))
+            }
+            last_id = Some(id);
+        }
+
+        let pattern = find_hf_pattern(&json["pre_tokenizer"]).ok_or_else(|| CounterError::ValueError(
+            "tokenizer.json has neither a Split pre-tokenizer with a regex nor a ByteLevel one using the regex.".to_string()))?;
+
+        // The byte-level BPE can't encode arbitrary text without a token for every single byte.
+        OpenAI::new(name, pattern, merge_able_ranks, special_tokens, None)?.with_byte_coverage_check()
+    }
+
+    fn to_hf_tokenizer_json(&self) -> CounterResult<Value> {
+        let byte_encoder = bytes_to_unicode();
+        let to_unicode = |bytes: &[u8]| {
+            bytes.iter().map(|byte| byte_encoder[byte]).collect::<String>()
+        };
+
+        let mut sorted_ranks = self.merge_able_ranks.iter().collect::<Vec<_>>();
+        sorted_ranks.sort_by_key(|(_, rank)| **rank);
+
+        let mut vocab = Map::new();
+        let mut merges = Vec::new();
+        for (bytes, rank) in sorted_ranks {
+            vocab.insert(to_unicode(bytes), json!(rank));
+            if bytes.len() > 1 {
+                let (first, second) = self.split_merged_token(bytes, *rank)?;
+                merges.push(json!(format!("{} {}", to_unicode(first), to_unicode(second))));
+            }
+        }
+
+        let mut specials = self.special_token.iter().collect::<Vec<_>>();
+        specials.sort_by_key(|(_, id)| **id);
+        let added_tokens = specials
+            .iter()
+            .map(|(content, id)| json!({
+                "id": id,
+                "content": content,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            }))
+            .collect::<Vec<_>>();
+
+        Ok(json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": added_tokens,
+            "normalizer": null,
+            "pre_tokenizer": {
+                "type": "Sequence",
+                "pretokenizers": [
+                    {
+                        "type": "Split",
+                        "pattern": {"Regex": self.pattern},
+                        "behavior": "Isolated",
+                        "invert": false,
+                    },
+                    {
+                        "type": "ByteLevel",
+                        "add_prefix_space": false,
+                        "trim_offsets": true,
+                        "use_regex": false,
+                    },
+                ],
+            },
+            "post_processor": null,
+            "decoder": {
+                "type": "ByteLevel",
+                "add_prefix_space": false,
+                "trim_offsets": true,
+                "use_regex": false,
+            },
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": null,
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "vocab": vocab,
+                "merges": merges,
+            },
+        }))
+    }
+
+    /// Finds the pair of the tokens merged into the given token by running BPE
+    /// only with the ranks lower than the token's rank.
+    fn split_merged_token<'b>(&self, bytes: &'b [u8], rank: u32) -> CounterResult<(&'b [u8], &'b [u8])> {
+        let get_rank = |part: &[u8]| {
+            self.merge_able_ranks.get(part).filter(|part_rank| **part_rank < rank).cloned()
+        };
+
+        let mut boundaries = (0..=bytes.len()).collect::<Vec<_>>();
+        while boundaries.len() > 3 {
+            let min_merge = boundaries
+                .windows(3)
+                .enumerate()
+                .filter_map(|(idx, window)| get_rank(&bytes[window[0]..window[2]]).map(|rank| (rank, idx)))
+                .min();
+
+            match min_merge {
+                Some((_, idx)) => {
+                    boundaries.remove(idx + 1);
+                }
+                None => break,
+            }
+        }
+
+        if boundaries.len() != 3 {
+            return Err(CounterError::ValueError(format!(
+                "merged token {:?} (rank {}) can't be split into two lower-ranked tokens.", bytes, rank)))
+        }
+
+        Ok((&bytes[..boundaries[1]], &bytes[boundaries[1]..]))
+    }
+}
+
+/// The pre-tokenization pattern of GPT-2, which the `ByteLevel` pre-tokenizer uses with `use_regex`.
+const HF_BYTE_LEVEL_PATTERN: &str = r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+/// Finds the pre-tokenization pattern from the pre-tokenizer section of `tokenizer.json`.
+fn find_hf_pattern(pre_tokenizer: &Value) -> Option<String> {
+    match pre_tokenizer["type"].as_str()? {
+        "Sequence" => pre_tokenizer["pretokenizers"]
+            .as_array()?
+            .iter()
+            .find_map(find_hf_pattern),
+        "Split" => pre_tokenizer["pattern"]["Regex"].as_str().map(|pattern| pattern.to_string()),
+        "ByteLevel" if pre_tokenizer["use_regex"].as_bool().unwrap_or(true) => Some(HF_BYTE_LEVEL_PATTERN.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs::{read, remove_file};
+    use serde_json::{json, Map, Value};
+    use uuid::Uuid;
+    use crate::errors::CounterError;
+    use crate::tokenizer::openai::load::bytes_to_unicode;
+    use crate::tokenizer::openai::{OpenAI, Specials};
+    use crate::tokenizer::openai::testing::{test_tokenizer, TEST_END_OF_TEXT};
+
+    #[test]
+    fn test_export_hf_tokenizer_json() {
+        let tokenizer = test_tokenizer();
+        let path = temp_dir().join(format!("{}.tokenizer.json", Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+
+        tokenizer.export_hf_tokenizer_json(path).unwrap();
+        let json = serde_json::from_slice::<Value>(&read(path).unwrap()).unwrap();
+        remove_file(path).unwrap();
+
+        assert_eq!(json["model"]["vocab"].as_object().unwrap().len(), tokenizer.vocab_len());
+        assert_eq!(json["model"]["merges"].as_array().unwrap().len(), tokenizer.vocab_len() - 256);
+        assert_eq!(json["added_tokens"].as_array().unwrap().len(), 1);
+
+        assert_eq!(json["model"]["type"], "BPE");
+        assert_eq!(json["model"]["vocab"]["Ġworld"], 264);
+        assert_eq!(json["model"]["merges"][0], "h e");
+        assert_eq!(json["model"]["merges"][8], "Ġwor ld");
+        assert_eq!(json["added_tokens"][0]["content"], "<|endoftext|>");
+    }
+
+    #[test]
+    fn test_from_hf_tokenizer_json() {
+        let path = temp_dir().join(format!("{}.tokenizer.json", Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let mut vocab = bytes_to_unicode()
+            .into_iter()
+            .map(|(byte, char)| (char.to_string(), json!(byte)))
+            .collect::<Map<_, _>>();
+        vocab.insert("he".to_string(), json!(256));
+        vocab.insert("Ġhe".to_string(), json!(257));
+        let json = json!({
+            "added_tokens": [{"id": 258, "content": "<|endoftext|>", "special": true}],
+            "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "use_regex": true},
+            "model": {
+                "type": "BPE",
+                "vocab": vocab,
+                "merges": ["h e", ["Ġ", "he"]],
+            },
+        });
+        std::fs::write(path, serde_json::to_vec(&json).unwrap()).unwrap();
+
+        let tokenizer = OpenAI::from_hf_tokenizer_json(path);
+        remove_file(path).unwrap();
+
+        let tokenizer = tokenizer.unwrap();
+        assert!(tokenizer.name().ends_with(".tokenizer"));
+        assert_eq!(tokenizer.encode_ordinary("hello he"), vec![256, b'l' as u32, b'l' as u32, b'o' as u32, 257]);
+        assert_eq!(tokenizer.end_of_text_token(), Some(258));
+        assert_eq!(tokenizer.decode_bytes(&tokenizer.encode_ordinary("hi \u{1F600}")), "hi \u{1F600}".as_bytes());
+    }
+
+    #[test]
+    fn test_hf_tokenizer_json_round_trip() {
+        let tokenizer = test_tokenizer();
+        let path = temp_dir().join(format!("{}.tokenizer.json", Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+
+        tokenizer.export_hf_tokenizer_json(path).unwrap();
+        let imported = OpenAI::from_hf_tokenizer_json(path);
+        remove_file(path).unwrap();
+
+        let imported = imported.unwrap();
+        assert_eq!(imported.pattern(), tokenizer.pattern());
+        assert_eq!(imported.iter_vocab(true).count(), tokenizer.iter_vocab(true).count());
+        assert_eq!(imported.encode_ordinary("hello the world"), tokenizer.encode_ordinary("hello the world"));
+    }
+
+    #[test]
+    fn test_from_hf_tokenizer_json_added_token_in_vocab() {
+        // GPT-2 and RoBERTa list <|endoftext|> both in the vocabulary and in the added tokens.
+        let tokenizer = test_tokenizer();
+        let mut json = tokenizer.to_hf_tokenizer_json().unwrap();
+        json["model"]["vocab"]["<|endoftext|>"] = json!(TEST_END_OF_TEXT);
+
+        let imported = OpenAI::from_hf_tokenizer_value("test".to_string(), &json).unwrap();
+        assert_eq!(imported.vocab_len(), tokenizer.vocab_len());
+        assert_eq!(imported.end_of_text_token(), Some(TEST_END_OF_TEXT));
+        assert_eq!(imported.encode("<|endoftext|>", Specials::All, Specials::All).unwrap(),
+                   vec![TEST_END_OF_TEXT]);
+        assert!(imported.to_hf_tokenizer_json().is_ok());
+    }
+
+    #[test]
+    fn test_from_hf_tokenizer_json_unsupported() {
+        let unigram = json!({"model": {"type": "Unigram", "vocab": []}});
+        assert!(matches!(OpenAI::from_hf_tokenizer_value("test".to_string(), &unigram),
+                         Err(CounterError::ValueError(_))));
+
+        let unordered = json!({
+            "pre_tokenizer": {"type": "ByteLevel"},
+            "model": {"type": "BPE", "vocab": {"h": 0, "e": 1, "he": 2, "eh": 3}, "merges": ["e h", "h e"]},
+        });
+        assert!(OpenAI::from_hf_tokenizer_value("test".to_string(), &unordered).is_err());
+
+        // Any character but "helo " couldn't be encoded without the other single bytes.
+        let partial = json!({
+            "pre_tokenizer": {"type": "ByteLevel"},
+            "model": {
+                "type": "BPE",
+                "vocab": {"h": 0, "e": 1, "l": 2, "o": 3, "Ġ": 4, "he": 5, "Ġhe": 6},
+                "merges": ["h e", ["Ġ", "he"]],
+            },
+        });
+        assert!(matches!(OpenAI::from_hf_tokenizer_value("test".to_string(), &partial),
+                         Err(CounterError::ValueError(_))));
+    }
+}
@@ -1,42 +1,166 @@
 use std::collections::HashMap;
 use std::env::temp_dir;
-use std::fs::{create_dir_all, File, remove_file, rename};
+use std::fs::{create_dir_all, File, rename};
+#[cfg(test)]
+use std::fs::remove_file;
 use std::io::{BufRead, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
+use std::thread::sleep;
+use std::time::Duration;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use bstr::ByteSlice;
+use rayon::prelude::*;
 use regex::Regex;
+#[cfg(feature = "download")]
+use reqwest::StatusCode;
 use sha2::{Digest, Sha256};
 use uuid::Uuid;
+use flate2::read::GzDecoder;
 use crate::errors::{CounterError, CounterResult};
 
-pub fn read_file(blobpath: &str) -> CounterResult<Vec<u8>> {
-    if !blobpath.starts_with("http://") && !blobpath.starts_with("https://") {
-        let path = Path::new(blobpath);
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// The number of the attempts to fetch a remote file unless `TIKTOKEN_FETCH_ATTEMPTS` is set.
+const DEFAULT_FETCH_ATTEMPTS: u32 = 3;
+/// The wait before the first retry, which is doubled for each following retry.
+pub(crate) const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+/// The connect and read timeout of fetching a remote file unless `TIKTOKEN_HTTP_TIMEOUT_SECS` is set.
+const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns whether the offline mode is enabled by `TIKTOKEN_OFFLINE`.
+/// In the offline mode, the remote files are never fetched and only the cached files can be used.
+pub(crate) fn is_offline() -> bool {
+    match std::env::var("TIKTOKEN_OFFLINE") {
+        Ok(val) => !val.is_empty() && val != "0",
+        Err(_) => false,
+    }
+}
 
-        let mut file = match File::open(path) {
-            Ok(file) => file,
-            Err(e) => return Err(CounterError::IOError(e.to_string())),
-        };
+/// Options of loading the vocabulary files, which override the environment variables per call.
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    /// The cache directory. If `None`, it is specified by the environment variables like `cache_dir`.
+    pub cache_dir: Option<PathBuf>,
+    /// The expected SHA-256 hash of the file.
+    pub expected_hash: Option<String>,
+    /// If `true`, the remote files are never fetched even if `TIKTOKEN_OFFLINE` is not set.
+    pub offline: bool,
+    /// The timeout of fetching a remote file. If `None`, it is specified like `http_timeout`.
+    pub timeout: Option<Duration>,
+}
+
+/// Reads the local file or fetches the remote file if the path is an HTTP(S) URL.
+/// The local path can be any `AsRef<Path>`, including the paths which are not valid UTF-8.
+pub fn read_file<P: AsRef<Path>>(blobpath: P) -> CounterResult<Vec<u8>> {
+    let path = blobpath.as_ref();
+    match path.to_str() {
+        Some(url) if is_url(url) => read_file_with(url, is_offline(), http_timeout()),
+        _ => read_local_file(path),
+    }
+}
+
+fn is_url(blobpath: &str) -> bool {
+    blobpath.starts_with("http://") || blobpath.starts_with("https://")
+}
+
+fn read_local_file(path: &Path) -> CounterResult<Vec<u8>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(CounterError::IOError(e.to_string())),
+    };
+
+    let mut content = Vec::new();
 
-        let mut content = Vec::new();
+    match file.read_to_end(&mut content) {
+        Ok(_) => Ok(content),
+        Err(e) => Err(CounterError::IOError(e.to_string())),
+    }
+}
+
+fn read_file_with(blobpath: &str, offline: bool, timeout: Duration) -> CounterResult<Vec<u8>> {
+    if !is_url(blobpath) {
+        return read_local_file(Path::new(blobpath))
+    }
+
+    if offline {
+        return Err(CounterError::IOError(format!("offline mode: refusing to fetch {}.", blobpath)))
+    }
 
-        return match file.read_to_end(&mut content) {
-            Ok(_) => Ok(content),
-            Err(e) => Err(CounterError::IOError(e.to_string())),
+    let attempts = fetch_attempts();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match fetch_once(blobpath, timeout) {
+            Ok(contents) => return Ok(contents),
+            Err((e, retryable)) => {
+                if !retryable || attempt >= attempts {
+                    return Err(e)
+                }
+            }
         }
+
+        sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
     }
+}
+
+/// Returns the number of the attempts to fetch a remote file specified by `TIKTOKEN_FETCH_ATTEMPTS`.
+/// If it is not set or invalid, `DEFAULT_FETCH_ATTEMPTS` is used.
+pub(crate) fn fetch_attempts() -> u32 {
+    std::env::var("TIKTOKEN_FETCH_ATTEMPTS")
+        .ok()
+        .and_then(|val| val.parse::<u32>().ok())
+        .map_or(DEFAULT_FETCH_ATTEMPTS, |attempts| attempts.max(1))
+}
 
-    let resp = reqwest::blocking::get(blobpath)
-        .map_err(|e| CounterError::IOError(e.to_string()))?
+/// Returns the timeout of fetching a remote file specified by `TIKTOKEN_HTTP_TIMEOUT_SECS`.
+/// If it is not set or invalid, `DEFAULT_HTTP_TIMEOUT` is used.
+pub(crate) fn http_timeout() -> Duration {
+    std::env::var("TIKTOKEN_HTTP_TIMEOUT_SECS")
+        .ok()
+        .and_then(|val| val.parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .map_or(DEFAULT_HTTP_TIMEOUT, Duration::from_secs)
+}
+
+/// Fetches a remote file once. The error is paired with whether it is worth retrying,
+/// which is true for the connection errors, the timeouts and the server errors but not for e.g. 404.
+#[cfg(feature = "download")]
+fn fetch_once(blobpath: &str, timeout: Duration) -> Result<Vec<u8>, (CounterError, bool)> {
+    let to_error = to_fetch_error;
+
+    let resp = reqwest::blocking::Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()
+        .and_then(|client| client.get(blobpath).send())
+        .and_then(|resp| resp.error_for_status())
+        .map_err(to_error)?
         .bytes()
-        .map_err(|e| CounterError::IOError(e.to_string()))?;
+        .map_err(to_error)?;
 
     Ok(resp.as_bytes().to_owned())
 }
 
+/// Converts the error of a request into `NetworkError` paired with whether it is retryable.
+/// It is shared by the blocking and the async fetches, so both retry the same errors.
+#[cfg(feature = "download")]
+pub(crate) fn to_fetch_error(e: reqwest::Error) -> (CounterError, bool) {
+    let retryable = match e.status() {
+        Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        None => true,
+    };
+    (CounterError::NetworkError(e.to_string()), retryable)
+}
+
+/// Without the `download` feature, only the local files and the cached files can be read.
+#[cfg(not(feature = "download"))]
+fn fetch_once(_blobpath: &str, _timeout: Duration) -> Result<Vec<u8>, (CounterError, bool)> {
+    Err((CounterError::IOError("download feature disabled".to_string()), false))
+}
+
 
 pub fn check_hash(data: &[u8], expected_hash: &str) -> bool {
     let mut hash = Sha256::new();
@@ -52,44 +176,67 @@ pub fn check_hash(data: &[u8], expected_hash: &str) -> bool {
     }
 }
 
-pub fn read_cached_file(blobpath: &str, expected_hash: Option<&str>) -> CounterResult<Vec<u8>> {
-    let mut user_specified_cache = true;
-
-    let cache_dir = if let Ok(val) = std::env::var("TIKTOKEN_CACHE_DIR") {
+/// Returns the cache directory specified by `TIKTOKEN_CACHE_DIR` or `DATA_GYM_CACHE_DIR`.
+/// If neither is set, `data-gym-cache` in the temporary directory is used.
+pub(crate) fn cache_dir() -> PathBuf {
+    if let Ok(val) = std::env::var("TIKTOKEN_CACHE_DIR") {
         Path::new(val.as_str()).to_path_buf()
     }
     else if let Ok(val) = std::env::var("DATA_GYM_CACHE_DIR") {
         Path::new(val.as_str()).to_path_buf()
     }
     else {
-        user_specified_cache = false;
         let dir = temp_dir();
         dir.join(Path::new("data-gym-cache"))
-    };
-
-    if cache_dir == Path::new("") {
-        return read_file(cache_dir.to_str().unwrap_or(""))
     }
+}
 
+/// Returns the file name of the cache for the blob path.
+pub(crate) fn cache_key(blobpath: &str) -> String {
     let mut cache_key_base = sha1::Sha1::new();
     cache_key_base.update(blobpath.as_bytes());
-    let cache_key = convert_to_hex(cache_key_base.finalize().as_bytes());
+    convert_to_hex(cache_key_base.finalize().as_bytes())
+}
 
-    let cache_path = cache_dir.join(Path::new(&cache_key));
+pub fn read_cached_file(blobpath: &str, expected_hash: Option<&str>) -> CounterResult<Vec<u8>> {
+    let options = LoadOptions {
+        expected_hash: expected_hash.map(|hash| hash.to_string()),
+        ..LoadOptions::default()
+    };
 
-    if cache_path.exists() {
-        if let Ok(mut file) = File::open(cache_path.clone()) {
-            let mut content = Vec::new();
+    read_cached_file_with_options(blobpath, &options)
+}
 
-            if let Ok(_) = file.read_to_end(&mut content) {
-                if expected_hash.is_some() {
-                    if check_hash(&content, expected_hash.unwrap()) {
-                        return Ok(content)
-                    }
-                }
+/// Reads the file through the cache like `read_cached_file` with the options given per call.
+/// The environment variables are used only for the options which are not specified.
+pub fn read_cached_file_with_options(blobpath: &str, options: &LoadOptions) -> CounterResult<Vec<u8>> {
+    let cache_dir = options.cache_dir.clone().unwrap_or_else(cache_dir);
+    let expected_hash = options.expected_hash.as_deref();
+    let read_file = |blobpath| {
+        match (options.offline, options.timeout) {
+            (false, None) => read_file(blobpath),
+            (offline, timeout) => {
+                read_file_with(blobpath, offline || is_offline(), timeout.unwrap_or_else(http_timeout))
             }
         }
-        remove_file(cache_path.clone()).map_err(|e| CounterError::IOError(e.to_string()))?;
+    };
+
+    if cache_dir == Path::new("") {
+        return read_file(blobpath)
+    }
+
+    let cache_path = cache_dir.join(Path::new(&cache_key(blobpath)));
+    let offline = options.offline || is_offline();
+
+    // The stale cache file is kept until the fetch succeeds and the rename below replaces it,
+    // so a failed fetch never loses the cache.
+    if let Ok(content) = std::fs::read(&cache_path) {
+        match expected_hash {
+            Some(hash_value) if check_hash(&content, hash_value) => return Ok(content),
+            // Without the hash, the cache is the only source in the offline mode.
+            None if offline => return Ok(content),
+            _ => {}
+        }
     }
 
     let contents = read_file(blobpath)?;
@@ -102,12 +249,11 @@ pub fn read_cached_file(blobpath: &str, expected_hash: Option<&str>) -> CounterR
         }
     }
 
-    create_dir_all(cache_dir).map_err(|e| CounterError::IOError(e.to_string()))?;
-
-    let tmp_filename = format!("{}.{}.tmp", cache_path.to_str().unwrap(), Uuid::new_v4().to_string());
+    create_dir_all(&cache_dir).map_err(|e| CounterError::IOError(e.to_string()))?;
 
-    let temp_file_name = Path::new(&tmp_filename);
-    match File::create(temp_file_name.clone()) {
+    // The temporary file is created next to the cache file, so the rename below is atomic.
+    let temp_file_name = cache_dir.join(format!("{}.{}.tmp", cache_key(blobpath), Uuid::new_v4()));
+    match File::create(&temp_file_name) {
         Ok(mut file) => {
             file.write_all(&contents).map_err(|e| CounterError::IOError(e.to_string()))?;
         }
@@ -120,30 +266,74 @@ pub fn read_cached_file(blobpath: &str, expected_hash: Option<&str>) -> CounterR
 
 }
 
+/// Returns the GPT-2 byte-to-unicode table mapping each byte to a printable character.
+/// The printable bytes except the space are mapped to themselves, and the others are
+/// mapped to the characters from U+0100 in the ascending order of the bytes.
+///
+/// This is the table used by the GPT-2 style vocabularies like `vocab.bpe` and `encoder.json`.
+pub fn bytes_to_unicode() -> HashMap<u8, char> {
+    let is_printable = |byte: u8| matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+
+    let mut table = HashMap::new();
+    let mut n = 0;
+    for byte in 0..=255_u8 {
+        if is_printable(byte) {
+            table.insert(byte, byte as char);
+        } else {
+            table.insert(byte, char::from_u32(256 + n).unwrap_or(char::REPLACEMENT_CHARACTER));
+            n += 1;
+        }
+    }
+
+    table
+}
+
+/// Returns the inverse of `bytes_to_unicode` mapping each printable character back to the byte.
+pub fn unicode_to_bytes() -> HashMap<char, u8> {
+    bytes_to_unicode()
+        .into_iter()
+        .map(|(byte, char)| (char, byte))
+        .collect()
+}
+
+fn decode_data_gym(value: &str, data_gym_byte_to_byte: &HashMap<char, u8>) -> CounterResult<Vec<u8>> {
+    let mut res = Vec::new();
+    for char in value.chars() {
+        match data_gym_byte_to_byte.get(&char) {
+            Some(value) => res.push(*value),
+            None => return Err(CounterError::KeyError(format!("{} not found in byte2byte dict", char))),
+        }
+    }
+    Ok(res)
+}
+
+/// Decodes each merge pair into the merged bytes. The merges are independent of each other,
+/// so they are decoded in parallel while the result keeps the order of the merges.
+fn decode_data_gym_merges(bpe_merges: &[(&str, &str)],
+                          data_gym_byte_to_byte: &HashMap<char, u8>
+) -> CounterResult<Vec<Vec<u8>>> {
+    bpe_merges
+        .par_iter()
+        .map(|(first, second)| {
+            let mut first_bytes = decode_data_gym(first, data_gym_byte_to_byte)?;
+            first_bytes.extend(decode_data_gym(second, data_gym_byte_to_byte)?);
+            Ok(first_bytes)
+        })
+        .collect()
+}
+
 pub fn data_gym_to_mergeable_bpe_ranks(vocab_bpe_file: &str,
                                        encoder_json_file: &str,
                                        vocab_bpe_hash: Option<&str>,
                                        encoder_json_hash: Option<&str>
 ) -> CounterResult<HashMap<Vec<u8>, u32>> {
-    let mut rank_to_intbyte = (0..=255).filter(|&b| {
-        let c = b as char;
-        c.is_ascii_graphic() && c != ' '
-    }).collect::<Vec<u8>>();
-
-    let mut data_gym_byte_to_byte = rank_to_intbyte.iter().map(|&byte| {
-        (byte as char, byte)
-    }).collect::<HashMap<_, _>>();
+    // The single byte tokens are ranked in the order of their characters,
+    // i.e. the printable bytes first and the others after them.
+    let mut rank_to_intbyte = (0..=255).collect::<Vec<u8>>();
+    let byte_to_data_gym = bytes_to_unicode();
+    rank_to_intbyte.sort_by_key(|byte| byte_to_data_gym[byte]);
 
-    let mut n: u8 = 0;
-
-    for b in 0..=255 {
-        if !rank_to_intbyte.contains(&b) {
-            rank_to_intbyte.push(b);
-            data_gym_byte_to_byte.insert((2_u8.pow(8) + n) as char, b);
-            n += 1;
-        }
-    }
-    assert_eq!(rank_to_intbyte.len(), 2_usize.pow(8));
+    let data_gym_byte_to_byte = unicode_to_bytes();
 
     // vocab_bpe contains the merges along with associated ranks
     let vocab_bpe = read_cached_file(vocab_bpe_file, vocab_bpe_hash)?;
@@ -152,10 +342,13 @@ pub fn data_gym_to_mergeable_bpe_ranks(vocab_bpe_file: &str,
         Err(e) => return Err(CounterError::ByteDecodeError(e.to_string())),
     };
 
+    // The first line is the version header like "#version: 0.2".
     let lines =
         vocab_bpe_contents
             .trim()
-            .lines().collect::<Vec<_>>();
+            .lines()
+            .filter(|line| !line.starts_with("#version"))
+            .collect::<Vec<_>>();
 
     let mut bpe_merges = Vec::new();
     let regex_pat =
@@ -168,17 +361,6 @@ pub fn data_gym_to_mergeable_bpe_ranks(vocab_bpe_file: &str,
         bpe_merges.push((split_values[0], split_values[1]));
     };
 
-    let decode_data_gym = |value: &str| -> CounterResult<Vec<u8>> {
-        let mut res = Vec::new();
-        for char in value.chars() {
-            match data_gym_byte_to_byte.get(&char) {
-                Some(value) => res.push(*value),
-                None => return Err(CounterError::KeyError(format!("{} not found in byte2byte dict", char))),
-            }
-        }
-        Ok(res)
-    };
-
     // add the single byte tokens
     let mut bpe_ranks =
         rank_to_intbyte
@@ -189,15 +371,10 @@ pub fn data_gym_to_mergeable_bpe_ranks(vocab_bpe_file: &str,
             })
             .collect::<HashMap<Vec<u8>, u32>>();
 
-    // add the merged tokens
-    let mut n = bpe_ranks.len() as u32;
-    for (first, second) in bpe_merges {
-        let mut first_bytes = decode_data_gym(first)?;
-        let second_bytes = decode_data_gym(second)?;
-        first_bytes.extend(second_bytes);
-        bpe_ranks.insert(first_bytes, n);
-        n += 1;
-    }
+    // add the merged tokens, which are decoded in parallel and ranked in the order of the merges
+    let first_merge_rank = bpe_ranks.len() as u32;
+    let merged_tokens = decode_data_gym_merges(&bpe_merges, &data_gym_byte_to_byte)?;
+    bpe_ranks.extend(merged_tokens.into_iter().zip(first_merge_rank..));
 
     // check that the encoder file matches the merges file
     // this sanity check is important since this code assumes that ranks are ordered the same
@@ -214,29 +391,32 @@ pub fn data_gym_to_mergeable_bpe_ranks(vocab_bpe_file: &str,
     let mut encoder_json_loaded = HashMap::new();
 
     for (key, value) in encoder_json {
-        let decoded_key = decode_data_gym(&key)?;
+        let decoded_key = decode_data_gym(&key, &data_gym_byte_to_byte)?;
         encoder_json_loaded.insert(decoded_key, value);
     }
 
     encoder_json_loaded.remove("<|endoftext|>".as_bytes());
     encoder_json_loaded.remove("<|startoftext|>".as_bytes());
 
-    assert!(
-        bpe_ranks
-            .iter()
-            .all(|(key, value)| encoder_json_loaded.get(key) == Some(&value)) &&
-            encoder_json_loaded
-                .iter()
-                .all(|(key, value)| bpe_ranks.get(key) == Some(&value)));
+    if bpe_ranks != encoder_json_loaded {
+        return Err(CounterError::ValueError(
+            "the ranks of the encoder file don't match the merges file.".to_string()))
+    }
 
     Ok(bpe_ranks)
 }
 
-pub fn dump_bpe(bpe_ranks: HashMap<Vec<u8>, u32>, bpe_file_path: &str) -> CounterResult<()> {
-    let path = Path::new(bpe_file_path);
-
-    let mut file = File::create(path).map_err(|e| CounterError::IOError(e.to_string()))?;
-
+/// Writes the merge ranks in the `.tiktoken` format sorted by the ranks.
+///
+/// # Arguments
+///
+/// * `bpe_ranks` - The merge ranks to be written.
+/// * `writer` - The sink like a file, an in-memory buffer or a compressor.
+///
+/// # Returns
+///
+/// A `CounterResult` which is `Err` when writing fails.
+pub fn dump_bpe_to_writer<W: Write>(bpe_ranks: &HashMap<Vec<u8>, u32>, writer: &mut W) -> CounterResult<()> {
     let mut sorted_bpe_ranks = bpe_ranks.iter().collect::<Vec<(_, _)>>();
     sorted_bpe_ranks.sort_by(|first, second| first.1.cmp(second.1));
 
@@ -244,38 +424,105 @@ pub fn dump_bpe(bpe_ranks: HashMap<Vec<u8>, u32>, bpe_file_path: &str) -> Counte
         let encoded_byte = BASE64_STANDARD.encode(bytes);
         let token_bytes = token.to_string();
 
-        file.write_all(encoded_byte.as_bytes()).map_err(|e| CounterError::IOError(e.to_string()))?;
-        file.write_all(" ".as_bytes()).map_err(|e| CounterError::IOError(e.to_string()))?;
-        file.write_all(token_bytes.as_bytes()).map_err(|e| CounterError::IOError(e.to_string()))?;
-        file.write_all("\n".as_bytes()).map_err(|e| CounterError::IOError(e.to_string()))?;
+        writer.write_all(encoded_byte.as_bytes()).map_err(|e| CounterError::IOError(e.to_string()))?;
+        writer.write_all(" ".as_bytes()).map_err(|e| CounterError::IOError(e.to_string()))?;
+        writer.write_all(token_bytes.as_bytes()).map_err(|e| CounterError::IOError(e.to_string()))?;
+        writer.write_all("\n".as_bytes()).map_err(|e| CounterError::IOError(e.to_string()))?;
     }
 
     Ok(())
 }
 
+pub fn dump_bpe(bpe_ranks: &HashMap<Vec<u8>, u32>, bpe_file_path: &str) -> CounterResult<()> {
+    let path = Path::new(bpe_file_path);
+
+    let mut file = File::create(path).map_err(|e| CounterError::IOError(e.to_string()))?;
+
+    dump_bpe_to_writer(bpe_ranks, &mut file)
+}
+
 pub fn load_bpe(bpe_file_path: &str,
                 expected_hash: Option<&str>
 ) -> CounterResult<HashMap<Vec<u8>, u32>> {
     let contents = read_cached_file(bpe_file_path, expected_hash)?;
+
+    load_bpe_from_bytes(&contents)
+}
+
+/// Loads the merge ranks from the local .tiktoken file given as a `Path`,
+/// e.g. a `PathBuf` which is not valid UTF-8. The local files are not cached.
+///
+/// # Arguments
+///
+/// * `path` - The path of the local .tiktoken file.
+/// * `expected_hash` - The expected SHA-256 hash of the file.
+///
+/// # Returns
+///
+/// A `CounterResult` containing the merge ranks.
+/// Returns `CounterError::ValueError` if the hash doesn't match.
+pub fn load_bpe_path<P: AsRef<Path>>(path: P,
+                                     expected_hash: Option<&str>
+) -> CounterResult<HashMap<Vec<u8>, u32>> {
+    let path = path.as_ref();
+    let contents = read_local_file(path)?;
+    if let Some(hash_value) = expected_hash {
+        if !check_hash(&contents, hash_value) {
+            return Err(CounterError::ValueError(format!(
+                "Hash mismatch for data read from {} (expected {}).", path.display(), hash_value)));
+        }
+    }
+
+    load_bpe_from_bytes(&contents)
+}
+
+/// Loads the merge ranks like `load_bpe` with the options given per call.
+pub fn load_bpe_with_options(bpe_file_path: &str,
+                             options: &LoadOptions
+) -> CounterResult<HashMap<Vec<u8>, u32>> {
+    let contents = read_cached_file_with_options(bpe_file_path, options)?;
+
+    load_bpe_from_bytes(&contents)
+}
+
+/// Loads the merge ranks from the contents of a .tiktoken file.
+/// The gzip-compressed contents are decompressed transparently.
+pub fn load_bpe_from_bytes(contents: &[u8]) -> CounterResult<HashMap<Vec<u8>, u32>> {
+    let decompressed;
+    let contents = if contents.starts_with(&GZIP_MAGIC) {
+        let mut decoder = GzDecoder::new(contents);
+        let mut buf = Vec::new();
+        decoder.read_to_end(&mut buf).map_err(|e| CounterError::IOError(e.to_string()))?;
+        decompressed = buf;
+        decompressed.as_slice()
+    } else {
+        contents
+    };
+
     let contents_str =
-        from_utf8(&contents).map_err(|e| CounterError::ByteDecodeError(e.to_string()))?;
+        from_utf8(contents).map_err(|e| CounterError::ByteDecodeError(e.to_string()))?;
 
     let mut bpe_dict = HashMap::new();
 
     let regex_pat =
         Regex::new(r"\s+").map_err(|e| CounterError::RegexError(e.to_string()))?;
-    for content in contents_str.lines() {
+    for (idx, content) in contents_str.lines().enumerate() {
+        let line_no = idx + 1;
         let split_value = regex_pat.split(content).collect::<Vec<_>>();
         if split_value.len() != 2 {
-            return Err(CounterError::ValueError("bpe dictionary can't split to pair. Please check input.".to_string()));
+            return Err(CounterError::ValueError(format!(
+                "bpe dictionary can't split to pair at line {} '{}'. Please check input.",
+                line_no, line_snippet(content))));
         }
 
         let bytes_value =
             BASE64_STANDARD.decode(split_value[0])
-                .map_err(|e| CounterError::Base64DecodeError(e.to_string()))?;
+                .map_err(|e| CounterError::Base64DecodeError(format!(
+                    "{} at line {} '{}'", e, line_no, line_snippet(content))))?;
         let token_value = match split_value[1].parse::<u32>() {
             Ok(val) => val,
-            Err(e) => return Err(CounterError::ValueError(e.to_string())),
+            Err(e) => return Err(CounterError::ValueError(format!(
+                "invalid rank: {} at line {} '{}'", e, line_no, line_snippet(content)))),
         };
 
         bpe_dict.insert(bytes_value, token_value);
@@ -284,10 +531,25 @@ pub fn load_bpe(bpe_file_path: &str,
     Ok(bpe_dict)
 }
 
+/// The maximum number of the characters of a line quoted in the error messages.
+const LINE_SNIPPET_CHARS: usize = 40;
+
+/// Truncates the line quoted in the error messages, since a corrupt file can have a huge line.
+fn line_snippet(line: &str) -> String {
+    match line.char_indices().nth(LINE_SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
 fn convert_to_hex(bytes: &[u8]) -> String {
     bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
 }
 
+/// Serializes the tests changing the environment variables read by the loaders.
+#[cfg(test)]
+pub(crate) static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
 #[test]
 fn test_check_hash() {
     let text = "test".as_bytes();
@@ -297,3 +559,341 @@ fn test_check_hash() {
             "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
         ))
 }
+
+#[test]
+fn test_load_bpe_gzip() {
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    let contents = "aGVsbG8= 0\nIHdvcmxk 1\nIQ== 2\n";
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(contents.as_bytes()).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let expected = load_bpe_from_bytes(contents.as_bytes()).unwrap();
+    assert_eq!(expected.len(), 3);
+    assert_eq!(expected[b" world".as_slice()], 1);
+    assert_eq!(load_bpe_from_bytes(&compressed).unwrap(), expected);
+}
+
+#[test]
+fn test_load_bpe_error_line() {
+    match load_bpe_from_bytes(b"aGVsbG8= 0\nIHdvcmxk 1\nIQ=!= 2\n") {
+        Err(CounterError::Base64DecodeError(message)) => {
+            assert!(message.contains("line 3 'IQ=!= 2'"), "{}", message)
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    match load_bpe_from_bytes(b"aGVsbG8= 0\nIHdvcmxk x1\n") {
+        Err(CounterError::ValueError(message)) => assert!(message.contains("line 2"), "{}", message),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let long_line = format!("{} 0", "A".repeat(1001));
+    match load_bpe_from_bytes(format!("{}\n", long_line).as_bytes()) {
+        Err(CounterError::Base64DecodeError(message)) => assert!(message.len() < 200, "{}", message),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_load_bpe_path() {
+    let contents = "aGVsbG8= 0\nIHdvcmxk 1\nIQ== 2\n";
+    let path = temp_dir().join(format!("{}.tiktoken", Uuid::new_v4()));
+    std::fs::write(&path, contents).unwrap();
+
+    let expected = load_bpe_from_bytes(contents.as_bytes()).unwrap();
+    assert_eq!(load_bpe_path(&path, None).unwrap(), expected);
+    assert_eq!(read_file(&path).unwrap(), contents.as_bytes());
+
+    let hash = convert_to_hex(Sha256::digest(contents.as_bytes()).as_slice());
+    assert_eq!(load_bpe_path(path.clone(), Some(&hash)).unwrap(), expected);
+    assert!(load_bpe_path(&path, Some("0000")).is_err());
+
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_read_cached_file_cache_miss() {
+    let dir = temp_dir().join(format!("data-gym-cache-test-{}", Uuid::new_v4()));
+    let blob = temp_dir().join(format!("{}.tiktoken", Uuid::new_v4()));
+    let blobpath = blob.to_str().unwrap();
+    std::fs::write(&blob, b"aGVsbG8= 0\n").unwrap();
+
+    let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("TIKTOKEN_CACHE_DIR", &dir);
+    let contents = read_cached_file(blobpath, None);
+    std::env::remove_var("TIKTOKEN_CACHE_DIR");
+
+    assert_eq!(contents.unwrap(), b"aGVsbG8= 0\n");
+    let cache_path = dir.join(cache_key(blobpath));
+    assert!(cache_path.is_file());
+    assert_eq!(std::fs::read(&cache_path).unwrap(), b"aGVsbG8= 0\n");
+    // Only the cache file remains in the cache directory.
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    remove_file(&blob).unwrap();
+}
+
+#[test]
+fn test_read_file_offline() {
+    let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("TIKTOKEN_OFFLINE", "1");
+    let result = read_file("https://openaipublic.blob.core.windows.net/encodings/r50k_base.tiktoken");
+    std::env::remove_var("TIKTOKEN_OFFLINE");
+
+    match result {
+        Err(CounterError::IOError(message)) => assert!(message.starts_with("offline mode: refusing to fetch")),
+        _ => panic!("the remote file must not be fetched in the offline mode."),
+    }
+
+    // The cached file is served without the hash and is never removed in the offline mode.
+    let url = "http://127.0.0.1:1/encodings/offline.tiktoken";
+    let dir = temp_dir().join(format!("data-gym-cache-test-{}", Uuid::new_v4()));
+    let cache_path = dir.join(cache_key(url));
+    create_dir_all(&dir).unwrap();
+    std::fs::write(&cache_path, b"aGVsbG8= 0\n").unwrap();
+
+    std::env::set_var("TIKTOKEN_OFFLINE", "1");
+    std::env::set_var("TIKTOKEN_CACHE_DIR", &dir);
+    let cached = read_cached_file(url, None);
+    let mismatched = read_cached_file(url, Some("0000"));
+    std::env::remove_var("TIKTOKEN_CACHE_DIR");
+    std::env::remove_var("TIKTOKEN_OFFLINE");
+
+    assert_eq!(cached.unwrap(), b"aGVsbG8= 0\n");
+    assert!(matches!(mismatched, Err(CounterError::IOError(_))));
+    assert_eq!(std::fs::read(&cache_path).unwrap(), b"aGVsbG8= 0\n");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "download")]
+#[test]
+fn test_read_file_network_error() {
+    let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("TIKTOKEN_FETCH_ATTEMPTS", "1");
+    // Nothing listens on the port 1, so the connection is refused.
+    let result = read_file("http://127.0.0.1:1/encodings/test.tiktoken");
+    std::env::remove_var("TIKTOKEN_FETCH_ATTEMPTS");
+
+    match result {
+        Err(CounterError::NetworkError(_)) => {}
+        other => panic!("expected NetworkError but got {:?}", other.map(|contents| contents.len())),
+    }
+}
+
+/// Serves the responses in order, one per connection, and returns the URL.
+#[cfg(all(test, feature = "download"))]
+pub(crate) fn serve_responses(responses: Vec<(&'static str, &'static str)>) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        for (status, body) in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let size = stream.read(&mut buf).unwrap();
+                if size == 0 {
+                    break
+                }
+                request.extend_from_slice(&buf[..size]);
+            }
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   status, body.len(), body).unwrap();
+        }
+    });
+
+    format!("http://{}/test.tiktoken", addr)
+}
+
+#[cfg(feature = "download")]
+#[test]
+fn test_read_file_retry() {
+    let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let url = serve_responses(vec![
+        ("503 Service Unavailable", ""),
+        ("503 Service Unavailable", ""),
+        ("200 OK", "aGVsbG8= 0\n"),
+    ]);
+    assert_eq!(read_file(&url).unwrap(), b"aGVsbG8= 0\n");
+
+    // 404 is not retried, so the following response is never served.
+    let url = serve_responses(vec![
+        ("404 Not Found", ""),
+        ("200 OK", "aGVsbG8= 0\n"),
+    ]);
+    assert!(matches!(read_file(&url), Err(CounterError::NetworkError(_))));
+}
+
+#[cfg(feature = "download")]
+#[test]
+fn test_read_file_timeout() {
+    // The connection is accepted by the backlog but never responded.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/test.tiktoken", listener.local_addr().unwrap());
+
+    let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("TIKTOKEN_FETCH_ATTEMPTS", "1");
+    std::env::set_var("TIKTOKEN_HTTP_TIMEOUT_SECS", "1");
+    let start = std::time::Instant::now();
+    let result = read_file(&url);
+    std::env::remove_var("TIKTOKEN_HTTP_TIMEOUT_SECS");
+
+    assert!(matches!(result, Err(CounterError::NetworkError(_))));
+    assert!(start.elapsed() < Duration::from_secs(10));
+
+    let options = LoadOptions {
+        cache_dir: Some(PathBuf::new()),
+        timeout: Some(Duration::from_millis(300)),
+        ..LoadOptions::default()
+    };
+    let start = std::time::Instant::now();
+    let result = read_cached_file_with_options(&url, &options);
+    std::env::remove_var("TIKTOKEN_FETCH_ATTEMPTS");
+
+    assert!(matches!(result, Err(CounterError::NetworkError(_))));
+    assert!(start.elapsed() < Duration::from_secs(10));
+    drop(listener);
+}
+
+#[cfg(not(feature = "download"))]
+#[test]
+fn test_read_file_without_download() {
+    let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    match read_file("http://127.0.0.1:1/encodings/test.tiktoken") {
+        Err(CounterError::IOError(message)) => assert_eq!(message, "download feature disabled"),
+        other => panic!("expected IOError but got {:?}", other.map(|contents| contents.len())),
+    }
+
+    // The local files are still loaded.
+    let contents = "aGVsbG8= 0\nIHdvcmxk 1\n";
+    let path = temp_dir().join(format!("{}.tiktoken", Uuid::new_v4()));
+    std::fs::write(&path, contents).unwrap();
+    assert_eq!(load_bpe(path.to_str().unwrap(), None).unwrap(), load_bpe_from_bytes(contents.as_bytes()).unwrap());
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_bytes_to_unicode() {
+    let table = bytes_to_unicode();
+    let inverse = unicode_to_bytes();
+    assert_eq!(table.len(), 256);
+    assert_eq!(inverse.len(), 256);
+
+    for byte in 0..=255_u8 {
+        assert_eq!(inverse[&table[&byte]], byte);
+    }
+    assert_eq!(table[&b'a'], 'a');
+    assert_eq!(table[&b' '], '\u{120}');
+    assert_eq!(table[&0xAD], '\u{143}');
+}
+
+#[test]
+fn test_data_gym_to_mergeable_bpe_ranks() {
+    let byte_encoder = bytes_to_unicode();
+    let to_unicode = |bytes: &[u8]| bytes.iter().map(|byte| byte_encoder[byte]).collect::<String>();
+
+    let mut single_bytes = (0..=255_u8).collect::<Vec<_>>();
+    single_bytes.sort_by_key(|byte| byte_encoder[byte]);
+    let mut encoder = single_bytes
+        .iter()
+        .enumerate()
+        .map(|(rank, byte)| (to_unicode(&[*byte]), rank as u32))
+        .collect::<HashMap<_, _>>();
+    encoder.insert(to_unicode(b" t"), 256);
+    encoder.insert(to_unicode(b"he"), 257);
+    encoder.insert(to_unicode(b" the"), 258);
+    encoder.insert("<|endoftext|>".to_string(), 259);
+
+    let vocab_bpe = format!("#version: 0.2\n{} {}\n{} {}\n{} {}\n",
+                            to_unicode(b" "), to_unicode(b"t"),
+                            to_unicode(b"h"), to_unicode(b"e"),
+                            to_unicode(b" t"), to_unicode(b"he"));
+
+    let id = Uuid::new_v4();
+    let vocab_bpe_path = temp_dir().join(format!("{}.vocab.bpe", id));
+    let encoder_json_path = temp_dir().join(format!("{}.encoder.json", id));
+    std::fs::write(&vocab_bpe_path, vocab_bpe).unwrap();
+    std::fs::write(&encoder_json_path, serde_json::to_vec(&encoder).unwrap()).unwrap();
+
+    let ranks = {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        data_gym_to_mergeable_bpe_ranks(
+            vocab_bpe_path.to_str().unwrap(), encoder_json_path.to_str().unwrap(), None, None)
+    };
+    remove_file(&vocab_bpe_path).unwrap();
+    remove_file(&encoder_json_path).unwrap();
+
+    let ranks = ranks.unwrap();
+    assert_eq!(ranks.len(), 259);
+    assert_eq!(ranks[b"!".as_slice()], 0);
+    // Same as the real GPT-2 vocabulary where "Ġ" is 220.
+    assert_eq!(ranks[b" ".as_slice()], 220);
+    assert_eq!(ranks[b" the".as_slice()], 258);
+}
+
+#[test]
+fn test_load_bpe_with_options() {
+    let dir = temp_dir().join(format!("data-gym-cache-test-{}", Uuid::new_v4()));
+    let blob = temp_dir().join(format!("{}.tiktoken", Uuid::new_v4()));
+    let blobpath = blob.to_str().unwrap();
+    std::fs::write(&blob, b"aGVsbG8= 0\nIHdvcmxk 1\n").unwrap();
+
+    let mut hash = Sha256::new();
+    Digest::update(&mut hash, b"aGVsbG8= 0\nIHdvcmxk 1\n");
+    let options = LoadOptions {
+        cache_dir: Some(dir.clone()),
+        expected_hash: Some(convert_to_hex(hash.finalize().as_slice())),
+        offline: true,
+        ..LoadOptions::default()
+    };
+
+    let ranks = load_bpe_with_options(blobpath, &options).unwrap();
+    assert_eq!(ranks[b" world".as_slice()], 1);
+    assert!(dir.join(cache_key(blobpath)).is_file());
+
+    // The cached file is served even after the original file is removed.
+    remove_file(&blob).unwrap();
+    assert_eq!(load_bpe_with_options(blobpath, &options).unwrap(), ranks);
+
+    assert!(read_cached_file_with_options("https://example.com/test.tiktoken", &options).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_dump_bpe_to_writer() {
+    let ranks = load_bpe_from_bytes(b"aGVsbG8= 0\nIHdvcmxk 1\nIQ== 2\n").unwrap();
+
+    let mut buffer = Vec::new();
+    dump_bpe_to_writer(&ranks, &mut buffer).unwrap();
+    assert_eq!(buffer, b"aGVsbG8= 0\nIHdvcmxk 1\nIQ== 2\n");
+    assert_eq!(load_bpe_from_bytes(&buffer).unwrap(), ranks);
+}
+
+#[test]
+fn test_decode_data_gym_merges() {
+    let byte_encoder = bytes_to_unicode();
+    let byte_decoder = unicode_to_bytes();
+    let to_unicode = |bytes: &[u8]| bytes.iter().map(|byte| byte_encoder[byte]).collect::<String>();
+
+    let pairs = (0..2000_u32)
+        .map(|i| (to_unicode(&i.to_le_bytes()[..2]), to_unicode(format!(" {}", i).as_bytes())))
+        .collect::<Vec<_>>();
+    let merges = pairs.iter().map(|(first, second)| (first.as_str(), second.as_str())).collect::<Vec<_>>();
+
+    let sequential = merges
+        .iter()
+        .map(|(first, second)| {
+            [decode_data_gym(first, &byte_decoder).unwrap(), decode_data_gym(second, &byte_decoder).unwrap()].concat()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(decode_data_gym_merges(&merges, &byte_decoder).unwrap(), sequential);
+
+    assert!(matches!(decode_data_gym_merges(&[("a", "\u{3042}")], &byte_decoder), Err(CounterError::KeyError(_))));
+}
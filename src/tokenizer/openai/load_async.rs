@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs::{create_dir_all, read, rename, write};
+use uuid::Uuid;
+use crate::tokenizer::openai::load::{cache_dir, cache_key, check_hash, fetch_attempts, http_timeout, is_offline,
+                                     load_bpe_from_bytes, to_fetch_error, INITIAL_BACKOFF};
+use crate::errors::{CounterError, CounterResult};
+
+/// Async version of `read_file` which doesn't block the thread of the async runtime.
+/// The failed fetch is retried like `read_file` up to `TIKTOKEN_FETCH_ATTEMPTS` times.
+pub async fn read_file_async(blobpath: &str) -> CounterResult<Vec<u8>> {
+    if !blobpath.starts_with("http://") && !blobpath.starts_with("https://") {
+        return read(Path::new(blobpath)).await.map_err(|e| CounterError::IOError(e.to_string()))
+    }
+
+    if is_offline() {
+        return Err(CounterError::IOError(format!("offline mode: refusing to fetch {}.", blobpath)))
+    }
+
+    let timeout = http_timeout();
+    let client = reqwest::Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()
+        .map_err(|e| CounterError::NetworkError(e.to_string()))?;
+
+    let attempts = fetch_attempts();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match fetch_once_async(&client, blobpath).await {
+            Ok(contents) => return Ok(contents),
+            Err((e, retryable)) => {
+                if !retryable || attempt >= attempts {
+                    return Err(e)
+                }
+            }
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+async fn fetch_once_async(client: &reqwest::Client, blobpath: &str) -> Result<Vec<u8>, (CounterError, bool)> {
+    let resp = client
+        .get(blobpath)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(to_fetch_error)?
+        .bytes()
+        .await
+        .map_err(to_fetch_error)?;
+
+    Ok(resp.to_vec())
+}
+
+/// Async version of `read_cached_file` sharing the same cache directory and keys.
+pub async fn read_cached_file_async(blobpath: &str, expected_hash: Option<&str>) -> CounterResult<Vec<u8>> {
+    let cache_dir = cache_dir();
+
+    if cache_dir == Path::new("") {
+        return read_file_async(blobpath).await
+    }
+
+    let cache_path = cache_dir.join(Path::new(&cache_key(blobpath)));
+
+    // Like `read_cached_file`, the stale cache file is replaced only after the fetch succeeds.
+    if let Ok(content) = read(&cache_path).await {
+        match expected_hash {
+            Some(hash_value) if check_hash(&content, hash_value) => return Ok(content),
+            None if is_offline() => return Ok(content),
+            _ => {}
+        }
+    }
+
+    let contents = read_file_async(blobpath).await?;
+    if let Some(hash_value) = expected_hash {
+        if !check_hash(&contents, hash_value) {
+            return Err(CounterError::ValueError(format!(
+                "Hash mismatch for data downloaded from {} (expected {}). \
+                This may indicate a corrupted download. Please try again.",
+                blobpath, hash_value)));
+        }
+    }
+
+    create_dir_all(&cache_dir).await.map_err(|e| CounterError::IOError(e.to_string()))?;
+
+    let tmp_filename = cache_dir.join(format!("{}.{}.tmp", cache_key(blobpath), Uuid::new_v4()));
+    write(&tmp_filename, &contents).await.map_err(|e| CounterError::IOError(e.to_string()))?;
+    rename(&tmp_filename, &cache_path).await.map_err(|e| CounterError::IOError(e.to_string()))?;
+
+    Ok(contents)
+}
+
+/// Async version of `load_bpe`.
+pub async fn load_bpe_async(bpe_file_path: &str,
+                            expected_hash: Option<&str>
+) -> CounterResult<HashMap<Vec<u8>, u32>> {
+    let contents = read_cached_file_async(bpe_file_path, expected_hash).await?;
+
+    load_bpe_from_bytes(&contents)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::errors::CounterError;
+    use crate::tokenizer::openai::load::{load_bpe_from_bytes, serve_responses, ENV_LOCK};
+    use crate::tokenizer::openai::load_async::{load_bpe_async, read_file_async};
+
+    #[tokio::test]
+    // The lock only keeps the other test threads from enabling the offline mode meanwhile.
+    #[allow(clippy::await_holding_lock)]
+    async fn test_load_bpe_async() {
+        let contents = "aGVsbG8= 0\nIHdvcmxk 1\nIQ== 2\n";
+        let url = serve_responses(vec![("200 OK", contents)]);
+
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let ranks = load_bpe_async(&url, None).await.unwrap();
+        assert_eq!(ranks, load_bpe_from_bytes(contents.as_bytes()).unwrap());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn test_read_file_async_status() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        // The error status is not returned as the contents, and 404 is not retried.
+        let url = serve_responses(vec![
+            ("404 Not Found", "not found"),
+            ("200 OK", "aGVsbG8= 0\n"),
+        ]);
+        assert!(matches!(read_file_async(&url).await, Err(CounterError::NetworkError(_))));
+
+        // The server errors are retried like the blocking fetch.
+        let url = serve_responses(vec![
+            ("503 Service Unavailable", ""),
+            ("500 Internal Server Error", ""),
+            ("200 OK", "aGVsbG8= 0\n"),
+        ]);
+        assert_eq!(read_file_async(&url).await.unwrap(), b"aGVsbG8= 0\n");
+
+        std::env::set_var("TIKTOKEN_FETCH_ATTEMPTS", "1");
+        let url = serve_responses(vec![("503 Service Unavailable", "")]);
+        let result = read_file_async(&url).await;
+        std::env::remove_var("TIKTOKEN_FETCH_ATTEMPTS");
+        assert!(matches!(result, Err(CounterError::NetworkError(_))));
+    }
+}
@@ -1,7 +1,28 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
 use crate::tokenizer::openai::OpenAI;
 use crate::tokenizer::openai::openai_sets::Models;
 use crate::errors::{CounterError, CounterResult};
 
+const MODEL_PREFIX_TO_O200K_BASE: [&str; 7] = [
+    "gpt-4o-",
+    "gpt-4.1-",
+    "chatgpt-4o-",
+    "o1-",
+    "o3-",
+    "o4-mini-",
+    "ft:gpt-4o"];
+
+const O200K_BASE: [&str; 5] = [
+    // chat
+    "gpt-4o",
+    "gpt-4.1",
+    // reasoning
+    "o1",
+    "o3",
+    "o4-mini",
+];
+
 const MODEL_PREFIX_TO_CL100K_BASE: [&str; 7] = [
     "gpt-4-",
     "gpt-3.5-turbo-",
@@ -75,9 +96,16 @@ const GPT2: [&str; 2] = [
     "gpt-2",
 ];
 
+/// The encodings compared by `count_all_models`, one for each model family.
+const MODEL_FAMILY_ENCODINGS: [&str; 5] = ["gpt2", "r50k_base", "p50k_base", "cl100k_base", "o200k_base"];
+
 /// Returns the name of the encoding used by a model user
 pub fn encoding_name_for_model(model_name: &str) -> CounterResult<String> {
-    let encoding_name = if CL100K_BASE.contains(&model_name) || MODEL_PREFIX_TO_CL100K_BASE.iter().any(|candidate_model| model_name.starts_with(candidate_model)) {
+    // "ft:gpt-4o" also starts with "ft:gpt-4", so o200k_base is checked first.
+    let encoding_name = if O200K_BASE.contains(&model_name) || MODEL_PREFIX_TO_O200K_BASE.iter().any(|candidate_model| model_name.starts_with(candidate_model)) {
+        "o200k_base"
+    }
+    else if CL100K_BASE.contains(&model_name) || MODEL_PREFIX_TO_CL100K_BASE.iter().any(|candidate_model| model_name.starts_with(candidate_model)) {
         "cl100k_base"
     }
     else if P50K_BASE.contains(&model_name) {
@@ -101,25 +129,190 @@ pub fn encoding_name_for_model(model_name: &str) -> CounterResult<String> {
 
 pub fn encoding_for_model(model_name: &str) -> CounterResult<OpenAI> {
     let encoding_name = encoding_name_for_model(model_name)?;
+
+    encoding_for_name(&encoding_name)
+}
+
+/// Returns the tokenizer of the encoding by its name, e.g. "cl100k_base".
+///
+/// Unlike `encoding_for_model`, the encodings that no model maps to are also available,
+/// e.g. "cl100k_im" adding the ChatML special tokens to cl100k_base.
+///
+/// # Arguments
+///
+/// * `encoding_name` - The name of the encoding.
+///
+/// # Returns
+///
+/// A `CounterResult` containing the tokenizer, `CounterError::ValueError` if the encoding is unknown,
+/// or the error of loading the merge ranks.
+pub fn encoding_for_name(encoding_name: &str) -> CounterResult<OpenAI> {
     let model = Models::try_from(encoding_name)?;
-    let input = model.get_input()?;
 
-    OpenAI::try_from(input)
+    OpenAI::try_from(model.get_input()?)
 }
 
+/// Returns the tokenizer used by a model from the process-wide cache.
+///
+/// The tokenizer is constructed at the first call for each encoding and shared by the following
+/// calls, so the merge table is downloaded and parsed only once. Distinct models using the same
+/// encoding (e.g. gpt-4 and gpt-3.5-turbo) share the same instance.
+pub fn encoding_for_model_cached(model_name: &str) -> CounterResult<Arc<OpenAI>> {
+    let encoding_name = encoding_name_for_model(model_name)?;
+
+    encoding_cached(&encoding_name)
+}
+
+/// Returns the tokenizer of the encoding from the process-wide cache shared with `encoding_for_model_cached`.
+fn encoding_cached(encoding_name: &str) -> CounterResult<Arc<OpenAI>> {
+    get_or_build_cached(encoding_name.to_string(), encoding_for_name)
+}
+
+/// Counts the tokens of the text by the encoding of every model family,
+/// e.g. to pick the cheapest encoding for the text.
+///
+/// The tokenizers are taken from the process-wide cache, so only the first call downloads
+/// and parses the vocabularies.
+///
+/// # Arguments
+///
+/// * `text` - The text to be counted. The special tokens are counted as ordinary text.
+///
+/// # Returns
+///
+/// A `CounterResult` containing the number of the tokens keyed by the encoding name,
+/// or the error of loading an encoding.
+pub fn count_all_models(text: &str) -> CounterResult<HashMap<&'static str, usize>> {
+    count_encodings(text, &MODEL_FAMILY_ENCODINGS, encoding_cached)
+}
+
+fn count_encodings<F>(text: &str,
+                      encoding_names: &[&'static str],
+                      get_encoding: F
+) -> CounterResult<HashMap<&'static str, usize>>
+where
+    F: Fn(&str) -> CounterResult<Arc<OpenAI>>
+{
+    let mut counts = HashMap::with_capacity(encoding_names.len());
+    for encoding_name in encoding_names {
+        counts.insert(*encoding_name, get_encoding(encoding_name)?.count_ordinary(text));
+    }
+
+    Ok(counts)
+}
+
+fn get_or_build_cached<F>(encoding_name: String, build: F) -> CounterResult<Arc<OpenAI>>
+where
+    F: FnOnce(&str) -> CounterResult<OpenAI>
+{
+    static CACHE: OnceLock<Mutex<HashMap<String, Arc<OpenAI>>>> = OnceLock::new();
+
+    // The lock is held while building, so concurrent first calls don't build the same encoding twice.
+    let mut cache = CACHE
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    if let Some(tokenizer) = cache.get(&encoding_name) {
+        return Ok(Arc::clone(tokenizer))
+    }
+
+    let tokenizer = Arc::new(build(&encoding_name)?);
+    cache.insert(encoding_name, Arc::clone(&tokenizer));
+
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
 mod test {
-    use crate::tokenizer::openai::models::encoding_for_model;
-    use crate::tokenizer::openai::Specials;
+    use std::sync::Arc;
+    use crate::tokenizer::openai::models::{count_encodings, encoding_for_model, encoding_for_name, encoding_name_for_model, get_or_build_cached, MODEL_FAMILY_ENCODINGS};
+    use crate::tokenizer::openai::{Models, OpenAI, Specials};
+    use crate::tokenizer::openai::testing::{model_ranks, test_tokenizer};
+    use crate::errors::CounterError;
 
     #[test]
     fn test_encoding() {
 
         let text = "GMOアドマーケティング";
         let encoder = encoding_for_model("gpt-4").unwrap();
+        assert_eq!(encoder.name(), "cl100k_base");
+        assert!(encoder.has_full_byte_coverage());
         let tokens = encoder.encode(text, Specials::Collection(&[]), Specials::All).unwrap();
         let token_count = tokens.len();
 
         eprintln!("{:?}", tokens);
         assert_eq!(token_count, 10)
     }
+
+    #[test]
+    fn test_encoding_for_name() {
+        assert!(matches!(encoding_for_name("cl100k"), Err(CounterError::ValueError(_))));
+
+        let model = Models::try_from("cl100k_im").unwrap();
+        assert_eq!(model, Models::CL100KBaseChat);
+        let tokenizer = OpenAI::from_model_ranks(model, model_ranks(model)).unwrap();
+        assert_eq!(tokenizer.name(), "cl100k_im");
+        assert_eq!(tokenizer.encode("<|im_start|>", Specials::All, Specials::All).unwrap(), vec![100264]);
+    }
+
+    #[test]
+    fn test_cached_encoding() {
+        let first = get_or_build_cached("test_cached".to_string(), |_| Ok(test_tokenizer())).unwrap();
+        let second = get_or_build_cached("test_cached".to_string(), |_| panic!("should be cached")).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let other = get_or_build_cached("test_cached_other".to_string(), |_| Ok(test_tokenizer())).unwrap();
+        assert!(!Arc::ptr_eq(&first, &other));
+
+        assert!(get_or_build_cached("test_cached_error".to_string(), |name| Err(
+            CounterError::ModelNotFound(name.to_string()))).is_err());
+        assert!(get_or_build_cached("test_cached_error".to_string(), |_| Ok(test_tokenizer())).is_ok());
+    }
+
+    #[test]
+    fn test_encoding_name_for_model() {
+        for (model_name, encoding_name) in [
+            ("gpt-4o", "o200k_base"),
+            ("gpt-4o-mini-2024-07-18", "o200k_base"),
+            ("ft:gpt-4o-2024-08-06:org::id", "o200k_base"),
+            ("o1-preview", "o200k_base"),
+            ("gpt-4", "cl100k_base"),
+            ("gpt-4-0613", "cl100k_base"),
+            ("ft:gpt-4-0613:org::id", "cl100k_base"),
+            ("text-davinci-003", "p50k_base"),
+            ("davinci", "r50k_base"),
+            ("gpt2", "gpt2"),
+        ] {
+            assert_eq!(encoding_name_for_model(model_name).unwrap(), encoding_name, "{}", model_name);
+        }
+        assert!(matches!(encoding_name_for_model("gpt-o"), Err(CounterError::ModelNotFound(_))));
+    }
+
+    #[test]
+    fn test_count_encodings() {
+        // Every encoding is built with its own pattern and special tokens,
+        // and a whole-piece token for the digits tells the patterns apart.
+        let counts = count_encodings("1234567", &MODEL_FAMILY_ENCODINGS, |encoding_name| {
+            let model = Models::try_from(encoding_name)?;
+            let mut ranks = model_ranks(model);
+            ranks.retain(|_, rank| *rank != 256);
+            ranks.insert(b"1234567".to_vec(), 256);
+            Ok(Arc::new(OpenAI::from_model_ranks(model, ranks)?))
+        }).unwrap();
+
+        let mut encoding_names = counts.keys().copied().collect::<Vec<_>>();
+        encoding_names.sort();
+        assert_eq!(encoding_names, vec!["cl100k_base", "gpt2", "o200k_base", "p50k_base", "r50k_base"]);
+        // The gpt2 family keeps the digits in one piece, while the later encodings split them by three.
+        assert_eq!(counts["gpt2"], 1);
+        assert_eq!(counts["r50k_base"], 1);
+        assert_eq!(counts["p50k_base"], 1);
+        assert_eq!(counts["cl100k_base"], 7);
+        assert_eq!(counts["o200k_base"], 7);
+
+        let result = count_encodings("hello", &MODEL_FAMILY_ENCODINGS, |name| Err(
+            CounterError::ModelNotFound(name.to_string())));
+        assert!(result.is_err());
+    }
 }
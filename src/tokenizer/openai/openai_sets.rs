@@ -6,72 +6,151 @@ use crate::errors::{CounterError, CounterResult};
 const ENDOFTEXT: &str = "<|endoftext|>";
 const FIM_PREFIX: &str = "<|fim_prefix|>";
 const FIM_MIDDLE: &str = "<|fim_middle|>";
-const FIM_SUFFIX: &str = "<|fim_middle|>";
+const FIM_SUFFIX: &str = "<|fim_suffix|>";
 const ENDOFPROMPT: &str = "<|endofprompt|>";
+const IM_START: &str = "<|im_start|>";
+const IM_END: &str = "<|im_end|>";
+const IM_SEP: &str = "<|im_sep|>";
 
+/// The probe text telling the pre-tokenization patterns apart by the contractions in the upper case,
+/// the long digits and the newlines, which is used by `OpenAI::sanity_check_pattern`.
+pub(crate) const PATTERN_PROBE: &str = "DON'T stop 1234567 _hello\r\n\r\n  world!!\n";
 
-#[derive(Copy, Clone)]
+
+/// The built-in encodings of the OpenAI models.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Models {
     GPT2,
     R50KBase,
     P50KBase,
     P50KEdit,
     CL100KBase,
+    /// `cl100k_base` with the ChatML role delimiters `<|im_start|>`, `<|im_end|>` and `<|im_sep|>`.
+    CL100KBaseChat,
+    O200KBase,
 }
 
 impl Models {
-    pub fn get_input(&self) -> CounterResult<OpenAIInput> {
+    /// Returns the canonical name of the encoding, which is accepted by `Models::try_from`.
+    pub fn encoding_name(&self) -> &'static str {
         match self {
-            Self::GPT2 => {
-                let merge_able_ranks = data_gym_to_mergeable_bpe_ranks(
-                    "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/vocab.bpe",
-                    "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/encoder.json",
-                    Some("1ce1664773c50f3e0cc8842619a93edc4624525b728b188a9e0be33b7726adc5"),
-                    Some("196139668be63f3b5d6574427317ae82f612a97c5d1cdaf36ed2256dbf636783"),
-                )?;
+            Self::GPT2 => "gpt2",
+            Self::R50KBase => "r50k_base",
+            Self::P50KBase => "p50k_base",
+            Self::P50KEdit => "p50k_edit",
+            Self::CL100KBase => "cl100k_base",
+            Self::CL100KBaseChat => "cl100k_im",
+            Self::O200KBase => "o200k_base",
+        }
+    }
+
+    /// Returns the number of the pieces which the pattern of the encoding splits `PATTERN_PROBE` into.
+    fn probe_pieces(&self) -> usize {
+        match self {
+            // "DON", "'", "T", " stop", " 1234567", " _", "hello", "\r\n\r\n ", " world", "!!", "\n"
+            Self::GPT2 | Self::R50KBase | Self::P50KBase | Self::P50KEdit => 11,
+            // "DON", "'T", " stop", " ", "123", "456", "7", " _", "hello", "\r\n\r\n", " ", " world", "!!\n"
+            Self::CL100KBase | Self::CL100KBaseChat => 13,
+            // "DON'T", " stop", " ", "123", "456", "7", " _", "hello", "\r\n\r\n", " ", " world", "!!\n"
+            Self::O200KBase => 12,
+        }
+    }
+
+    /// Returns the number of the mergeable ranks in the vocabulary file of the encoding.
+    pub(crate) fn ranks_len(&self) -> usize {
+        match self {
+            Self::GPT2 | Self::R50KBase => 50256,
+            Self::P50KBase | Self::P50KEdit => 50280,
+            Self::CL100KBase | Self::CL100KBaseChat => 100256,
+            Self::O200KBase => 199998,
+        }
+    }
+
+    /// Downloads (or reads from the cache) the mergeable ranks of the encoding.
+    fn load_ranks(&self) -> CounterResult<HashMap<Vec<u8>, u32>> {
+        match self {
+            Self::GPT2 => data_gym_to_mergeable_bpe_ranks(
+                "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/vocab.bpe",
+                "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/encoder.json",
+                Some("1ce1664773c50f3e0cc8842619a93edc4624525b728b188a9e0be33b7726adc5"),
+                Some("196139668be63f3b5d6574427317ae82f612a97c5d1cdaf36ed2256dbf636783"),
+            ),
+            Self::R50KBase => load_bpe(
+                "https://openaipublic.blob.core.windows.net/encodings/r50k_base.tiktoken",
+                Some("306cd27f03c1a714eca7108e03d66b7dc042abe8c258b44c199a7ed9838dd930"),
+            ),
+            Self::P50KBase | Self::P50KEdit => load_bpe(
+                "https://openaipublic.blob.core.windows.net/encodings/p50k_base.tiktoken",
+                Some("94b5ca7dff4d00767bc256fdd1b27e5b17361d7b8a5f968547f9f23eb70d2069"),
+            ),
+            Self::CL100KBase | Self::CL100KBaseChat => load_bpe(
+                "https://openaipublic.blob.core.windows.net/encodings/cl100k_base.tiktoken",
+                Some("223921b76ee99bde995b7ff738513eef100fb51d18c93597a113bcffe865b2a7"),
+            ),
+            Self::O200KBase => load_bpe(
+                "https://openaipublic.blob.core.windows.net/encodings/o200k_base.tiktoken",
+                Some("446a9538cb6c348e3516120d7c08b09f57c36495e2acfffe59a5bf8b0cfb1a2d"),
+            ),
+        }
+    }
+
+    pub(crate) fn get_input(&self) -> CounterResult<OpenAIInput> {
+        self.get_input_with_ranks(self.load_ranks()?)
+    }
+
+    /// Returns the input of the encoding using the preloaded mergeable ranks instead of downloading them.
+    /// The pattern, the special tokens and the number of the vocabulary are filled in from the encoding.
+    ///
+    /// # Arguments
+    ///
+    /// * `merge_able_ranks` - The preloaded mergeable ranks, e.g. from the vendored vocabulary file.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the `OpenAIInput`,
+    /// or `CounterError::ValueError` if the number of the ranks doesn't match the encoding.
+    pub(crate) fn get_input_with_ranks(&self, merge_able_ranks: HashMap<Vec<u8>, u32>) -> CounterResult<OpenAIInput> {
+        if merge_able_ranks.len() != self.ranks_len() {
+            return Err(CounterError::ValueError(format!(
+                "'{}' expects {} mergeable ranks but {} were given.",
+                self.encoding_name(), self.ranks_len(), merge_able_ranks.len())))
+        }
 
+        match self {
+            Self::GPT2 => {
                 Ok(OpenAIInput {
-                    name: "gpt2".to_string(),
+                    name: self.encoding_name().to_string(),
                     pattern: r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+".to_string(),
                     merge_able_ranks,
                     special_tokens: [(ENDOFTEXT.to_string(), 50256)].iter().cloned().collect(),
                     explicit_n_vocab: Some(50257),
+                    expected_probe_pieces: Some(self.probe_pieces()),
+                    require_full_byte_coverage: true,
                 })
             }
             Self::R50KBase => {
-                let merge_able_ranks = load_bpe(
-                    "https://openaipublic.blob.core.windows.net/encodings/r50k_base.tiktoken",
-                    Some("306cd27f03c1a714eca7108e03d66b7dc042abe8c258b44c199a7ed9838dd930"),
-                )?;
-
                 Ok(OpenAIInput {
-                    name: "r50k_base".to_string(),
+                    name: self.encoding_name().to_string(),
                     pattern: r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+".to_string(),
                     merge_able_ranks,
                     special_tokens: [(ENDOFTEXT.to_string(), 50256)].iter().cloned().collect(),
                     explicit_n_vocab: Some(50257),
+                    expected_probe_pieces: Some(self.probe_pieces()),
+                    require_full_byte_coverage: true,
                 })
             }
             Self::P50KBase => {
-                let merge_able_ranks = load_bpe(
-                    "https://openaipublic.blob.core.windows.net/encodings/p50k_base.tiktoken",
-                    Some("94b5ca7dff4d00767bc256fdd1b27e5b17361d7b8a5f968547f9f23eb70d2069"),
-                )?;
-
                 Ok(OpenAIInput {
-                    name: "p50k_base".to_string(),
+                    name: self.encoding_name().to_string(),
                     pattern: r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+".to_string(),
                     merge_able_ranks,
                     special_tokens: [(ENDOFTEXT.to_string(), 50256)].iter().cloned().collect(),
                     explicit_n_vocab: Some(50281),
+                    expected_probe_pieces: Some(self.probe_pieces()),
+                    require_full_byte_coverage: true,
                 })
             }
             Self::P50KEdit => {
-                let merge_able_ranks = load_bpe(
-                    "https://openaipublic.blob.core.windows.net/encodings/p50k_base.tiktoken",
-                    Some("94b5ca7dff4d00767bc256fdd1b27e5b17361d7b8a5f968547f9f23eb70d2069"),
-                )?;
-
                 let special_tokens = [
                     (ENDOFTEXT.to_string(), 50256),
                     (FIM_PREFIX.to_string(), 50281),
@@ -80,19 +159,16 @@ impl Models {
                 ].iter().cloned().collect::<HashMap<_, u32>>();
 
                 Ok(OpenAIInput {
-                    name: "p50k_edit".to_string(),
+                    name: self.encoding_name().to_string(),
                     pattern: r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+".to_string(),
                     merge_able_ranks,
                     special_tokens,
                     explicit_n_vocab: None,
+                    expected_probe_pieces: Some(self.probe_pieces()),
+                    require_full_byte_coverage: true,
                 })
             }
             Self::CL100KBase => {
-                let merge_able_ranks = load_bpe(
-                    "https://openaipublic.blob.core.windows.net/encodings/cl100k_base.tiktoken",
-                    Some("223921b76ee99bde995b7ff738513eef100fb51d18c93597a113bcffe865b2a7"),
-                )?;
-
                 let special_tokens = [
                     (ENDOFTEXT.to_string(), 100257),
                     (FIM_PREFIX.to_string(), 100258),
@@ -102,30 +178,165 @@ impl Models {
                 ].iter().cloned().collect::<HashMap<_, u32>>();
 
                 Ok(OpenAIInput {
-                    name: "cl100k_base".to_string(),
-                    pattern: r"'[sdmt]|ll|ve|re|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]++[\r\n]*|\s*[\r\n]|\s+[^(\w)]|\s+".to_string(),
+                    name: self.encoding_name().to_string(),
+                    pattern: r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]++[\r\n]*|\s*[\r\n]|\s+(?!\S)|\s+".to_string(),
+                    merge_able_ranks,
+                    special_tokens,
+                    explicit_n_vocab: None,
+                    expected_probe_pieces: Some(self.probe_pieces()),
+                    require_full_byte_coverage: true,
+                })
+            }
+            Self::CL100KBaseChat => {
+                let mut input = Self::CL100KBase.get_input_with_ranks(merge_able_ranks)?;
+                input.name = self.encoding_name().to_string();
+                input.special_tokens.extend([
+                    (IM_START.to_string(), 100264),
+                    (IM_END.to_string(), 100265),
+                    (IM_SEP.to_string(), 100266),
+                ]);
+
+                Ok(input)
+            }
+            Self::O200KBase => {
+                let special_tokens = [
+                    (ENDOFTEXT.to_string(), 199999),
+                    (ENDOFPROMPT.to_string(), 200018),
+                ].iter().cloned().collect::<HashMap<_, u32>>();
+
+                Ok(OpenAIInput {
+                    name: self.encoding_name().to_string(),
+                    pattern: r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+".to_string(),
                     merge_able_ranks,
                     special_tokens,
                     explicit_n_vocab: None,
+                    expected_probe_pieces: Some(self.probe_pieces()),
+                    require_full_byte_coverage: true,
                 })
             }
         }
     }
 }
 
-impl TryFrom<String> for Models {
+impl TryFrom<&str> for Models {
     type Error = CounterError;
 
-    fn try_from(value: String) -> Result<Self, Self::Error>   {
-        let model =  match value.as_str() {
+    fn try_from(value: &str) -> Result<Self, Self::Error>   {
+        let model =  match value {
             "gpt2" => Self::GPT2,
             "r50k_base" => Self::R50KBase,
             "p50k_base" => Self::P50KBase,
             "p50k_edit" => Self::P50KEdit,
             "cl100k_base" => Self::CL100KBase,
+            "cl100k_im" => Self::CL100KBaseChat,
+            "o200k_base" => Self::O200KBase,
             _ => return Err(CounterError::ValueError(format!("'{}' model not found from the openai tokenizers.", value))),
         };
 
         Ok(model)
     }
 }
+
+impl TryFrom<String> for Models {
+    type Error = CounterError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error>   {
+        Self::try_from(value.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::tokenizer::openai::{OpenAI, Specials};
+    use crate::tokenizer::openai::openai_sets::Models;
+    use crate::tokenizer::openai::testing::model_ranks;
+
+    #[test]
+    fn test_encoding_name_round_trip() {
+        let models = [Models::GPT2, Models::R50KBase, Models::P50KBase, Models::P50KEdit, Models::CL100KBase,
+                      Models::CL100KBaseChat, Models::O200KBase];
+        for model in models {
+            let name = model.encoding_name();
+            assert_eq!(Models::try_from(name).unwrap().encoding_name(), name);
+            assert_eq!(Models::try_from(name.to_string()).unwrap().encoding_name(), name);
+        }
+
+        assert!(Models::try_from("unknown_base").is_err());
+    }
+
+    #[test]
+    fn test_get_input_with_ranks() {
+        let ranks = (0..100256u32)
+            .map(|rank| (rank.to_be_bytes().to_vec(), rank))
+            .collect::<HashMap<_, _>>();
+
+        let input = Models::CL100KBase.get_input_with_ranks(ranks.clone()).unwrap();
+        assert_eq!(input.name, "cl100k_base");
+        assert_eq!(input.merge_able_ranks, ranks);
+        assert_eq!(input.special_tokens["<|endoftext|>"], 100257);
+        assert_eq!(input.special_tokens["<|endofprompt|>"], 100276);
+        assert_eq!(input.special_tokens["<|fim_suffix|>"], 100260);
+
+        // The number of the ranks is for cl100k_base, not r50k_base.
+        assert!(Models::R50KBase.get_input_with_ranks(ranks).is_err());
+    }
+
+    #[test]
+    fn test_cl100k_pattern() {
+        let mut ranks = (0..=255_u8)
+            .map(|byte| (vec![byte], byte as u32))
+            .collect::<HashMap<_, _>>();
+        for (rank, merge) in [b"do".as_slice(), b"don", b"'t", b" w"].into_iter().enumerate() {
+            ranks.insert(merge.to_vec(), 256 + rank as u32);
+        }
+        // The rest of the ranks are unreachable by the merges.
+        for rank in ranks.len() as u32..100256 {
+            ranks.insert([&[0xff, 0xfe], rank.to_be_bytes().as_slice()].concat(), rank);
+        }
+
+        let input = Models::CL100KBase.get_input_with_ranks(ranks).unwrap();
+        let tokenizer = OpenAI::try_from(input).unwrap();
+
+        // The contraction is split from the word even in the upper case.
+        assert_eq!(tokenizer.encode_ordinary("don't"), vec![257, 258]);
+        assert_eq!(tokenizer.encode_ordinary("DON'T"), vec![b'D' as u32, b'O' as u32, b'N' as u32, b'\'' as u32, b'T' as u32]);
+        // The last space before a word is split from the other spaces by the look-ahead.
+        assert_eq!(tokenizer.encode_ordinary("do  world"),
+                   vec![256, b' ' as u32, 259, b'o' as u32, b'r' as u32, b'l' as u32, b'd' as u32]);
+        assert_eq!(tokenizer.decode_bytes(&tokenizer.encode_ordinary("don't  stop\n\n")), b"don't  stop\n\n");
+        assert_eq!(tokenizer.pretokenize("Hello world!"), vec!["Hello", " world", "!"]);
+        assert!(tokenizer.sanity_check_pattern());
+        // The cl100k ranks with the gpt2 pattern fail the sanity check.
+        let gpt2_pattern = Models::GPT2.get_input_with_ranks((0..50256).map(|rank: u32| (rank.to_be_bytes().to_vec(), rank)).collect())
+            .unwrap()
+            .pattern;
+        assert!(!tokenizer.clone().with_pattern(&gpt2_pattern).unwrap().sanity_check_pattern());
+        assert_eq!(tokenizer.pretokenize("do  12345"), vec!["do", " ", " ", "123", "45"]);
+    }
+
+    #[test]
+    fn test_cl100k_chat_specials() {
+        let input = Models::CL100KBaseChat.get_input_with_ranks(model_ranks(Models::CL100KBaseChat)).unwrap();
+        assert_eq!(input.name, "cl100k_im");
+        assert_eq!(input.special_tokens["<|endoftext|>"], 100257);
+        let tokenizer = OpenAI::try_from(input).unwrap();
+
+        let tokens = tokenizer.encode("<|im_start|>user", Specials::All, Specials::All).unwrap();
+        assert_eq!(tokens[0], 100264);
+        assert_eq!(tokenizer.decode_bytes(&tokens[1..]), b"user");
+        assert!(tokenizer.encode("<|im_end|>", Specials::Collection(&[]), Specials::All).is_err());
+    }
+
+    #[test]
+    fn test_o200k_pattern() {
+        let tokenizer = OpenAI::from_model_ranks(Models::O200KBase, model_ranks(Models::O200KBase)).unwrap();
+        assert_eq!(tokenizer.name(), "o200k_base");
+        assert_eq!(tokenizer.encode("<|endofprompt|>", Specials::All, Specials::All).unwrap(), vec![200018]);
+
+        // The words are split at the case changes and the contraction is kept with the word.
+        assert_eq!(tokenizer.pretokenize("helloWorld DON'T 12345"), vec!["hello", "World", " DON'T", " ", "123", "45"]);
+        assert_eq!(tokenizer.pretokenize("Hello world!"), vec!["Hello", " world", "!"]);
+        assert!(tokenizer.sanity_check_pattern());
+    }
+}
@@ -0,0 +1,462 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read};
+use std::str::from_utf8;
+use crate::tokenizer::openai::OpenAI;
+use crate::errors::{CounterError, CounterResult};
+
+/// The number of trailing regex pieces held back until more text arrives.
+/// A piece can be merged with the following text (e.g. "hel" + "lo") and the contraction
+/// like "'ll" can change the boundary of the piece before the last one.
+const UNSTABLE_PIECES: usize = 2;
+
+/// The size of the buffer which `ReaderEncoder` reads at once.
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Incremental encoder for the chunked input like a large file or a network stream.
+///
+/// The trailing regex pieces which can be changed by the following chunk are buffered
+/// across the chunk boundaries, so the concatenation of all tokens returned by `push` and
+/// `finish` is identical to `encode_ordinary` of the concatenated text.
+pub struct Encoder<'a> {
+    tokenizer: &'a OpenAI,
+    buffer: String,
+    incomplete_bytes: Vec<u8>,
+}
+
+impl <'a> Encoder<'a> {
+    pub fn new(tokenizer: &'a OpenAI) -> Self {
+        Self {
+            tokenizer,
+            buffer: String::new(),
+            incomplete_bytes: Vec::new(),
+        }
+    }
+
+    /// Pushes a chunk of text and returns the tokens which are already stable.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk` - The next chunk of the text.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `u32` values representing the encoded tokens. It can be empty when
+    /// the whole buffered text may still change.
+    pub fn push(&mut self, chunk: &str) -> Vec<u32> {
+        self.buffer.push_str(chunk);
+
+        // If the regex fails, the whole buffer is kept until `finish`, which falls back like `encode_ordinary`.
+        let (tokens, end) = self.tokenizer.bpe_base
+            .encode_ordinary_stable(&self.buffer, UNSTABLE_PIECES)
+            .unwrap_or_default();
+        self.buffer.drain(..end);
+
+        tokens
+    }
+
+    /// Pushes a chunk of bytes and returns the tokens which are already stable.
+    ///
+    /// A multibyte UTF-8 character straddling the chunk boundary is kept until the next chunk
+    /// completes it.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk` - The next chunk of the UTF-8 bytes.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the encoded tokens,
+    /// or `CounterError::ByteDecodeError` if the bytes are not valid UTF-8.
+    pub fn push_bytes(&mut self, chunk: &[u8]) -> CounterResult<Vec<u32>> {
+        self.incomplete_bytes.extend_from_slice(chunk);
+
+        let valid_up_to = match from_utf8(&self.incomplete_bytes) {
+            Ok(_) => self.incomplete_bytes.len(),
+            Err(e) => {
+                if e.error_len().is_some() {
+                    return Err(CounterError::ByteDecodeError(e.to_string()))
+                }
+                e.valid_up_to()
+            }
+        };
+
+        let bytes = self.incomplete_bytes.drain(..valid_up_to).collect::<Vec<_>>();
+        // The bytes are validated above.
+        let text = from_utf8(&bytes).map_err(|e| CounterError::ByteDecodeError(e.to_string()))?;
+
+        Ok(self.push(text))
+    }
+
+    /// Encodes the rest of the buffered text.
+    ///
+    /// If an incomplete UTF-8 sequence remains from `push_bytes`,
+    /// its bytes are encoded by BPE as they are.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `u32` values representing the rest of the encoded tokens.
+    pub fn finish(self) -> Vec<u32> {
+        let mut tokens = self.tokenizer.encode_ordinary(&self.buffer);
+        if !self.incomplete_bytes.is_empty() {
+            tokens.extend(self.tokenizer.bpe_base.encode_single_piece(&self.incomplete_bytes));
+        }
+
+        tokens
+    }
+}
+
+/// Lazy encoder reading the UTF-8 bytes from a reader in fixed-size buffers.
+///
+/// The partial UTF-8 characters and regex pieces are carried over the buffer boundaries by
+/// `Encoder`, so the yielded tokens are identical to `encode_ordinary` of the whole input
+/// without buffering the whole input in memory. Created by `OpenAI::encode_reader`.
+pub struct ReaderEncoder<'a, R: Read> {
+    encoder: Option<Encoder<'a>>,
+    reader: R,
+    buffer: Vec<u8>,
+    pending: VecDeque<u32>,
+}
+
+impl <'a, R: Read> ReaderEncoder<'a, R> {
+    fn new(tokenizer: &'a OpenAI, reader: R) -> Self {
+        Self {
+            encoder: Some(Encoder::new(tokenizer)),
+            reader,
+            buffer: vec![0; READ_BUFFER_SIZE],
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl <'a, R: Read> Iterator for ReaderEncoder<'a, R> {
+    type Item = CounterResult<u32>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.pending.pop_front() {
+                return Some(Ok(token))
+            }
+
+            let encoder = self.encoder.as_mut()?;
+            let size = match self.reader.read(&mut self.buffer) {
+                Ok(size) => size,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.encoder = None;
+                    return Some(Err(CounterError::IOError(e.to_string())))
+                }
+            };
+
+            if size == 0 {
+                // The encoder is dropped after finishing, so the iteration ends once `pending` is empty.
+                if let Some(encoder) = self.encoder.take() {
+                    self.pending.extend(encoder.finish());
+                }
+                continue
+            }
+
+            match encoder.push_bytes(&self.buffer[..size]) {
+                Ok(tokens) => self.pending.extend(tokens),
+                Err(e) => {
+                    self.encoder = None;
+                    return Some(Err(e))
+                }
+            }
+        }
+    }
+}
+
+impl OpenAI {
+    /// Encodes the UTF-8 bytes read from the reader lazily, ignoring the special tokens.
+    ///
+    /// The reader is read in fixed-size buffers as the iterator advances,
+    /// so a large corpus can be encoded (or counted) without loading it into a `String`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The reader of the UTF-8 text.
+    ///
+    /// # Returns
+    ///
+    /// An iterator yielding the encoded tokens. It yields `CounterError::IOError` if reading fails
+    /// or `CounterError::ByteDecodeError` if the bytes are not valid UTF-8, and then ends.
+    pub fn encode_reader<R: Read>(&self, reader: R) -> ReaderEncoder<'_, R> {
+        ReaderEncoder::new(self, reader)
+    }
+}
+
+/// Incremental decoder for the streaming output of the LLM.
+///
+/// Tokens are pushed one at a time and the decoded text is flushed as soon as it reaches
+/// a complete UTF-8 boundary. The trailing bytes of an incomplete multibyte character
+/// (e.g. an emoji split across two tokens) are held back until the next token completes them.
+pub struct DecodeStream<'a> {
+    tokenizer: &'a OpenAI,
+    buffer: Vec<u8>,
+}
+
+impl <'a> DecodeStream<'a> {
+    pub fn new(tokenizer: &'a OpenAI) -> Self {
+        Self {
+            tokenizer,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Pushes a token and returns the text which can be decoded completely.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The next token.
+    ///
+    /// # Returns
+    ///
+    /// A `CounterResult` containing the decoded text, or `None` when all the bytes are held back.
+    /// The invalid bytes which can't be completed by any following bytes are replaced with "\u{FFFD}".
+    /// Returns `CounterError::KeyError` if the token is unknown.
+    pub fn push(&mut self, token: u32) -> CounterResult<Option<String>> {
+        let bytes = self.tokenizer.bpe_base.decode_single_token_bytes(token)?;
+        self.buffer.extend_from_slice(&bytes);
+
+        let mut decoded = String::new();
+        let mut start = 0;
+        loop {
+            match from_utf8(&self.buffer[start..]) {
+                Ok(valid) => {
+                    decoded.push_str(valid);
+                    start = self.buffer.len();
+                    break
+                }
+                Err(e) => {
+                    let valid_end = start + e.valid_up_to();
+                    decoded.push_str(&String::from_utf8_lossy(&self.buffer[start..valid_end]));
+                    match e.error_len() {
+                        Some(error_len) => {
+                            decoded.push(char::REPLACEMENT_CHARACTER);
+                            start = valid_end + error_len;
+                        }
+                        None => {
+                            // The incomplete character at the end is held back.
+                            start = valid_end;
+                            break
+                        }
+                    }
+                }
+            }
+        }
+        self.buffer.drain(..start);
+
+        if decoded.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(decoded))
+        }
+    }
+
+    /// Flushes the held back bytes, which are replaced with "\u{FFFD}" since the stream ends
+    /// in the middle of a character.
+    pub fn finish(self) -> Option<String> {
+        if self.buffer.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&self.buffer).into_owned())
+        }
+    }
+}
+
+/// Rolling window of the most recent tokens, e.g. the context of a long-running chat session.
+///
+/// Only the pushed text is encoded, so the prior text is never re-encoded. The oldest tokens
+/// are evicted from the front once the number of the tokens exceeds the capacity.
+#[derive(Clone, Debug)]
+pub struct TokenWindow {
+    capacity: usize,
+    tokens: Vec<u32>,
+}
+
+impl TokenWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tokens: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Returns the maximum number of the tokens in the window.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Encodes the text and appends the tokens, evicting the oldest tokens beyond the capacity.
+    /// If the text alone exceeds the capacity, only its last tokens are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to be appended.
+    /// * `tok` - The tokenizer to encode the text.
+    pub fn push_text(&mut self, text: &str, tok: &OpenAI) {
+        let tokens = tok.encode_ordinary(text);
+        if tokens.len() >= self.capacity {
+            self.tokens.clear();
+            self.tokens.extend_from_slice(&tokens[tokens.len() - self.capacity..]);
+            return
+        }
+
+        let overflow = (self.tokens.len() + tokens.len()).saturating_sub(self.capacity);
+        self.tokens.drain(..overflow);
+        self.tokens.extend(tokens);
+    }
+
+    /// Returns the tokens in the window from the oldest one.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read};
+    use crate::tokenizer::openai::stream::{DecodeStream, Encoder, TokenWindow};
+    use crate::tokenizer::openai::{Models, OpenAI};
+    use crate::tokenizer::openai::testing::{model_pattern, test_tokenizer};
+    use crate::errors::CounterResult;
+
+    const TEXT: &str = "hello world, the thing isn't nothing.\n\n  caf\u{e9} \u{1F600}\u{1F600} we'll  \tsee 12345 ";
+
+    /// Returns the test tokenizer with the test pattern and with the real cl100k and gpt2 patterns,
+    /// whose look-ahead and digit splitting the streaming must agree with.
+    fn pattern_tokenizers() -> Vec<OpenAI> {
+        vec![
+            test_tokenizer(),
+            test_tokenizer().with_pattern(&model_pattern(Models::CL100KBase)).unwrap(),
+            test_tokenizer().with_pattern(&model_pattern(Models::GPT2)).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_encoder_chunks() {
+        for tokenizer in pattern_tokenizers() {
+            let expected = tokenizer.encode_ordinary(TEXT);
+
+            for chunk_size in [1, 3, TEXT.len()] {
+                let mut encoder = Encoder::new(&tokenizer);
+                let mut tokens = Vec::new();
+                for chunk in TEXT.as_bytes().chunks(chunk_size) {
+                    tokens.extend(encoder.push_bytes(chunk).unwrap());
+                }
+                tokens.extend(encoder.finish());
+
+                assert_eq!(tokens, expected, "pattern: {}, chunk size: {}", tokenizer.pattern(), chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoder_str_chunks() {
+        for tokenizer in pattern_tokenizers() {
+            let mut encoder = Encoder::new(&tokenizer);
+            let mut tokens = Vec::new();
+            for char in TEXT.chars() {
+                tokens.extend(encoder.push(char.to_string().as_str()));
+            }
+            tokens.extend(encoder.finish());
+
+            assert_eq!(tokens, tokenizer.encode_ordinary(TEXT), "pattern: {}", tokenizer.pattern());
+        }
+    }
+
+    #[test]
+    fn test_encoder_invalid_bytes() {
+        let tokenizer = test_tokenizer();
+        let mut encoder = Encoder::new(&tokenizer);
+
+        assert!(encoder.push_bytes(b"hello \xff").is_err());
+    }
+
+    /// Reader returning at most 3 bytes at once to split the characters and the pieces.
+    struct SmallReads<'a>(&'a [u8]);
+
+    impl Read for SmallReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = self.0.len().min(buf.len()).min(3);
+            buf[..size].copy_from_slice(&self.0[..size]);
+            self.0 = &self.0[size..];
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn test_encode_reader() {
+        let tokenizer = test_tokenizer();
+        let expected = tokenizer.encode_ordinary(TEXT);
+
+        let tokens = tokenizer.encode_reader(Cursor::new(TEXT.as_bytes())).collect::<CounterResult<Vec<_>>>();
+        assert_eq!(tokens.unwrap(), expected);
+
+        let tokens = tokenizer.encode_reader(SmallReads(TEXT.as_bytes())).collect::<CounterResult<Vec<_>>>();
+        assert_eq!(tokens.unwrap(), expected);
+
+        let mut tokens = tokenizer.encode_reader(Cursor::new(b"hello \xff world"));
+        assert!(tokens.next().unwrap().is_err());
+        assert!(tokens.next().is_none());
+    }
+
+    #[test]
+    fn test_decode_stream() {
+        let tokenizer = test_tokenizer();
+        let text = "hello \u{1F600} caf\u{e9} world";
+        let tokens = tokenizer.encode_ordinary(text);
+
+        let mut stream = DecodeStream::new(&tokenizer);
+        let mut outputs = Vec::new();
+        for token in tokens {
+            outputs.push(stream.push(token).unwrap());
+        }
+
+        // The emoji is split into 2 tokens, so the first one doesn't produce any text.
+        assert!(outputs.iter().any(|output| output.is_none()));
+        assert!(outputs.iter().flatten().all(|output| !output.contains('\u{FFFD}')));
+        assert_eq!(outputs.into_iter().flatten().collect::<String>(), text);
+        assert_eq!(stream.finish(), None);
+    }
+
+    #[test]
+    fn test_decode_stream_invalid() {
+        let tokenizer = test_tokenizer();
+        let mut stream = DecodeStream::new(&tokenizer);
+
+        assert_eq!(stream.push(0xf0).unwrap(), None);
+        assert_eq!(stream.push(b'a' as u32).unwrap(), Some("\u{FFFD}a".to_string()));
+        assert_eq!(stream.push(0xc3).unwrap(), None);
+        assert_eq!(stream.finish(), Some("\u{FFFD}".to_string()));
+        assert!(DecodeStream::new(&tokenizer).push(tokenizer.n_vocab() as u32).is_err());
+    }
+
+    #[test]
+    fn test_token_window() {
+        let tokenizer = test_tokenizer();
+        let messages = ["hello world", " the thing", " caf\u{e9} \u{1F600}", " we'll see"];
+
+        let mut window = TokenWindow::new(8);
+        let mut all_tokens = Vec::new();
+        for message in messages {
+            window.push_text(message, &tokenizer);
+            all_tokens.extend(tokenizer.encode_ordinary(message));
+
+            assert!(window.tokens().len() <= window.capacity());
+            let start = all_tokens.len().saturating_sub(window.capacity());
+            assert_eq!(window.tokens(), &all_tokens[start..]);
+        }
+        assert_eq!(window.tokens().len(), 8);
+
+        // A text exceeding the capacity by itself keeps only its tail.
+        let long_text = "hello world ".repeat(10);
+        window.push_text(&long_text, &tokenizer);
+        let tokens = tokenizer.encode_ordinary(&long_text);
+        assert_eq!(window.tokens(), &tokens[tokens.len() - 8..]);
+
+        let mut empty = TokenWindow::new(0);
+        empty.push_text("hello", &tokenizer);
+        assert!(empty.tokens().is_empty());
+    }
+}
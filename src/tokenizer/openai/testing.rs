@@ -0,0 +1,86 @@
+use std::collections::{HashMap, HashSet};
+use crate::tokenizer::openai::{Models, OpenAI, OpenAIInput};
+
+/// Pre-tokenization pattern of the GPT-2 family without the look-ahead branch.
+pub(crate) const TEST_PATTERN: &str =
+    r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+";
+
+/// Merged tokens of the test vocabulary in rank order.
+/// Every entry is made from two tokens having the lower rank, so BPE can reach it.
+const TEST_MERGES: [&[u8]; 18] = [
+    b"he",
+    b"ll",
+    b"llo",
+    b"hello",
+    b" w",
+    b"or",
+    b" wor",
+    b"ld",
+    b" world",
+    b"in",
+    b"ing",
+    b"th",
+    b"the",
+    b" the",
+    b" t",
+    b"\xc3\xa9",
+    b"\xf0\x9f",
+    b"\xf0\x9f\x98",
+];
+
+/// The `<|endoftext|>` token id of the test vocabulary.
+pub(crate) const TEST_END_OF_TEXT: u32 = 256 + TEST_MERGES.len() as u32;
+
+/// Returns the merge ranks having all the single bytes and a few merged tokens.
+pub(crate) fn test_ranks() -> HashMap<Vec<u8>, u32> {
+    let mut ranks = (0..=255_u8)
+        .map(|byte| (vec![byte], byte as u32))
+        .collect::<HashMap<_, _>>();
+
+    for (idx, merge) in TEST_MERGES.iter().enumerate() {
+        ranks.insert(merge.to_vec(), 256 + idx as u32);
+    }
+
+    ranks
+}
+
+/// Returns the merge ranks having all the single bytes and as many ranks as the vocabulary of the model,
+/// where the ranks other than the single bytes are unreachable by the merges.
+/// The ids of the special tokens are skipped as in the real vocabulary, e.g. 50256 of p50k_base.
+pub(crate) fn model_ranks(model: Models) -> HashMap<Vec<u8>, u32> {
+    let placeholder = (0..model.ranks_len() as u32).map(|rank| (rank.to_be_bytes().to_vec(), rank)).collect();
+    let special_ids = model.get_input_with_ranks(placeholder).unwrap()
+        .special_tokens
+        .into_values()
+        .collect::<HashSet<_>>();
+
+    (0..=255_u8)
+        .map(|byte| (vec![byte], byte as u32))
+        .chain((256..)
+            .filter(|rank| !special_ids.contains(rank))
+            .take(model.ranks_len() - 256)
+            .map(|rank: u32| ([&[0xff, 0xfe], rank.to_be_bytes().as_slice()].concat(), rank)))
+        .collect()
+}
+
+/// Returns the pre-tokenization pattern of the model, e.g. to check the real pattern with the test vocabulary.
+pub(crate) fn model_pattern(model: Models) -> String {
+    model.get_input_with_ranks(model_ranks(model)).unwrap().pattern
+}
+
+/// Returns a small offline tokenizer input so that tests don't need to download a vocabulary.
+pub(crate) fn test_input() -> OpenAIInput {
+    OpenAIInput {
+        name: "test_base".to_string(),
+        pattern: TEST_PATTERN.to_string(),
+        merge_able_ranks: test_ranks(),
+        special_tokens: [("<|endoftext|>".to_string(), TEST_END_OF_TEXT)].iter().cloned().collect(),
+        explicit_n_vocab: Some(TEST_END_OF_TEXT + 1),
+        expected_probe_pieces: None,
+        require_full_byte_coverage: false,
+    }
+}
+
+pub(crate) fn test_tokenizer() -> OpenAI {
+    OpenAI::try_from(test_input()).unwrap()
+}
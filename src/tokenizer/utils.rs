@@ -204,7 +204,7 @@ impl <'a> BytesChunk<'a> {
     }
 }
 
-fn from_utf8_or<'a>(v: &'a [u8], replace_bytes: &'a str) -> Cow<'a, str> {
+pub fn from_utf8_or<'a>(v: &'a [u8], replace_bytes: &'a str) -> Cow<'a, str> {
     let mut iter = BytesChunks::new(v);
 
     let first_valid = if let Some(chunk) = iter.next() {
@@ -236,6 +236,16 @@ pub fn from_utf8_ignore(v: &[u8]) -> Cow<str> {
     from_utf8_or(v, "")
 }
 
+/// Replaces every maximal invalid byte sequence with "\u{FFFD}", same as `String::from_utf8_lossy`.
+pub fn from_utf8_replace(v: &[u8]) -> Cow<'_, str> {
+    from_utf8_or(v, "\u{FFFD}")
+}
+
+/// Counts the bytes which are not a part of any valid UTF-8 character.
+pub fn count_invalid_bytes(v: &[u8]) -> usize {
+    BytesChunks::new(v).map(|chunk| chunk.invalid().len()).sum()
+}
+
 pub fn from_utf8_backslash(v: &[u8]) -> Cow<str> {
     let mut iter = BytesChunks::new(v);
 
@@ -269,3 +279,37 @@ pub fn from_utf8_backslash(v: &[u8]) -> Cow<str> {
 
     Cow::Owned(res)
 }
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use crate::tokenizer::utils::{count_invalid_bytes, from_utf8_replace};
+
+    const INVALID_INPUTS: [&[u8]; 7] = [
+        b"hello\xffworld",
+        b"\xff\xfe",
+        b"caf\xc3",
+        b"\xf0\x9f\x98hello",
+        b"\xed\xa0\x80",
+        b"\xe3\x81\x82\x80\xe3",
+        b"\xc0\xafend",
+    ];
+
+    #[test]
+    fn test_from_utf8_replace() {
+        assert!(matches!(from_utf8_replace(b"hello"), Cow::Borrowed("hello")));
+        assert!(matches!(from_utf8_replace(b""), Cow::Borrowed("")));
+
+        for input in INVALID_INPUTS {
+            assert_eq!(from_utf8_replace(input), String::from_utf8_lossy(input), "input: {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_count_invalid_bytes() {
+        assert_eq!(count_invalid_bytes(b"hello \xf0\x9f\x98\x80"), 0);
+        assert_eq!(count_invalid_bytes(b"hello\xffworld"), 1);
+        assert_eq!(count_invalid_bytes(b"\xf0\x9f\x98hello"), 3);
+        assert_eq!(count_invalid_bytes(b"\xe3\x81\x82\x80\xe3"), 2);
+    }
+}